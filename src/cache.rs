@@ -0,0 +1,363 @@
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use serde_json::{json, Value};
+
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::parser::include_search_dirs;
+use crate::parser::lexer::{lex_line_tokens, LexError, LexedToken};
+use crate::parser::object::Image;
+use crate::parser::source::SourceProvider;
+use crate::{AssembleOptions, Assembly};
+
+/// Token types of each line of a file, or the error lexing the line.
+pub type LexedLines = Rc<[Result<Vec<LexedToken>, LexError>]>;
+
+/// 64-bit FNV-1a hash, stable between runs unlike the hasher of `HashMap`.
+pub fn content_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+struct LexedFile {
+    source_code: String,
+    lines: LexedLines,
+    /// Lexed again since the last `evict_unused`
+    used: bool,
+}
+
+/// Lexed files by hash of their contents. A file included from several places is lexed
+/// once, and an edited file is lexed again since its hash changes.
+#[derive(Default)]
+pub struct LexCache {
+    files: RefCell<HashMap<u64, LexedFile>>,
+}
+
+impl LexCache {
+    pub fn lex(&self, source_code: &str) -> LexedLines {
+        let hash = content_hash(source_code.as_bytes());
+        let mut files = self.files.borrow_mut();
+        match files.get_mut(&hash) {
+            Some(file) if file.source_code == source_code => {
+                file.used = true;
+                file.lines.clone()
+            }
+            _ => {
+                let lines = source_code
+                    .lines()
+                    .map(lex_line_tokens)
+                    .collect::<LexedLines>();
+                files.insert(
+                    hash,
+                    LexedFile {
+                        source_code: source_code.to_string(),
+                        lines: lines.clone(),
+                        used: true,
+                    },
+                );
+                lines
+            }
+        }
+    }
+
+    /// Forget the files not lexed since the previous call, e.g. old versions of edited files.
+    pub fn evict_unused(&self) {
+        self.files
+            .borrow_mut()
+            .retain(|_, file| std::mem::replace(&mut file.used, false));
+    }
+
+    /// Number of distinct file contents held.
+    pub fn len(&self) -> usize {
+        self.files.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.borrow().is_empty()
+    }
+}
+
+/// Provider recording the hash of every file read through it, and the paths probed
+/// without finding a file, e.g. the candidates of an include before the one found.
+struct RecordingSources<'a> {
+    sources: &'a dyn SourceProvider,
+    hashes: RefCell<HashMap<PathBuf, u64>>,
+    missing: RefCell<BTreeSet<PathBuf>>,
+}
+
+impl SourceProvider for RecordingSources<'_> {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, String> {
+        let bytes = self.sources.read(path)?;
+        self.hashes
+            .borrow_mut()
+            .insert(path.to_path_buf(), content_hash(&bytes));
+        Ok(bytes)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let exists = self.sources.exists(path);
+        if !exists {
+            self.missing.borrow_mut().insert(path.to_path_buf());
+        }
+        exists
+    }
+
+    fn canonicalize(&self, path: &Path) -> PathBuf {
+        self.sources.canonicalize(path)
+    }
+}
+
+/// Assemblies without errors kept on disk between runs, along with the hash of every
+/// file they were assembled from.
+pub struct BuildCache {
+    dir: PathBuf,
+}
+
+impl BuildCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// File of the assembly of `path` with `options`, any change of them is another entry.
+    fn entry_path(&self, path: &str, options: &AssembleOptions) -> PathBuf {
+        let mut key = format!("{}\n{}\n", env!("CARGO_PKG_VERSION"), path);
+        for dir in include_search_dirs(&options.include_dirs) {
+            key.push_str(&format!("I {}\n", dir.display()));
+        }
+        for (name, value) in options.defines.iter() {
+            key.push_str(&format!("D {}={}\n", name, value));
+        }
+        for memory in options.linker_config.memories.iter() {
+            key.push_str(&format!(
+                "M {} {} {}\n",
+                memory.name, memory.start, memory.size
+            ));
+        }
+        for section in options.linker_config.sections.iter() {
+            key.push_str(&format!(
+                "S {} {} {:?}\n",
+                section.name, section.load, section.run
+            ));
+        }
+        self.dir
+            .join(format!("{:016x}.json", content_hash(key.as_bytes())))
+    }
+
+    /// Cached assembly of `path` if none of the files it was assembled from changed,
+    /// else the result of `assemble` which is kept for the next runs.
+    pub fn get_or_assemble(
+        &self,
+        path: &str,
+        sources: &dyn SourceProvider,
+        options: &AssembleOptions,
+        assemble: impl FnOnce(&dyn SourceProvider) -> Assembly,
+    ) -> Assembly {
+        let entry_path = self.entry_path(path, options);
+        if let Some(mut assembly) = fs::read_to_string(&entry_path)
+            .ok()
+            .and_then(|str| load(&str, sources))
+        {
+            assembly.diagnostics.push(Diagnostic::info(format!(
+                "Reusing the cached assembly of {}",
+                path
+            )));
+            return assembly;
+        }
+
+        // Hashes are taken while assembling, a file edited meanwhile is assembled again next time
+        let recording = RecordingSources {
+            sources,
+            hashes: RefCell::new(HashMap::new()),
+            missing: RefCell::new(BTreeSet::new()),
+        };
+        let mut assembly = assemble(&recording);
+        let Some(entry) = store(
+            &assembly,
+            &recording.hashes.borrow(),
+            &recording.missing.borrow(),
+        ) else {
+            return assembly;
+        };
+        if let Err(err) =
+            fs::create_dir_all(&self.dir).and_then(|_| fs::write(&entry_path, entry.to_string()))
+        {
+            assembly.diagnostics.push(Diagnostic::info(format!(
+                "Cannot write the build cache {}: {}",
+                entry_path.display(),
+                err
+            )));
+        }
+        assembly
+    }
+}
+
+fn load(str: &str, sources: &dyn SourceProvider) -> Option<Assembly> {
+    let entry: Value = serde_json::from_str(str).ok()?;
+
+    let mut dependencies = vec![];
+    for dependency in entry["dependencies"].as_array()? {
+        let path = PathBuf::from(dependency["path"].as_str()?);
+        let bytes = sources.read(&path).ok()?;
+        if dependency["hash"].as_str()? != format!("{:016x}", content_hash(&bytes)) {
+            return None;
+        }
+        dependencies.push(path);
+    }
+
+    // A file created where an include was searched first shadows the file found then
+    for path in entry["missing"].as_array()? {
+        if sources.exists(Path::new(path.as_str()?)) {
+            return None;
+        }
+    }
+
+    let mut chunks = vec![];
+    for chunk in entry["chunks"].as_array()? {
+        let addr = u16::try_from(chunk["addr"].as_u64()?).ok()?;
+        let bytes = chunk["bytes"]
+            .as_array()?
+            .iter()
+            .map(|byte| byte.as_u64().and_then(|byte| u8::try_from(byte).ok()))
+            .collect::<Option<Vec<u8>>>()?;
+        chunks.push((addr, bytes));
+    }
+
+    let mut diagnostics = vec![];
+    for diagnostic in entry["diagnostics"].as_array()? {
+        diagnostics.push(Diagnostic {
+            severity: Severity::Info,
+            message: diagnostic["message"].as_str()?.to_string(),
+            line: diagnostic["line"].as_u64().map(|line| line as usize),
+        });
+    }
+
+    Some(Assembly {
+        image: Some(Image { chunks }),
+        diagnostics,
+        dependencies,
+    })
+}
+
+fn store(
+    assembly: &Assembly,
+    hashes: &HashMap<PathBuf, u64>,
+    missing: &BTreeSet<PathBuf>,
+) -> Option<Value> {
+    if assembly.has_errors() {
+        return None;
+    }
+    let image = assembly.image.as_ref()?;
+    let mut dependencies = vec![];
+    for dependency in assembly.dependencies.iter() {
+        dependencies.push(json!({
+            "path": dependency.to_string_lossy(),
+            "hash": format!("{:016x}", hashes.get(dependency)?),
+        }));
+    }
+    Some(json!({
+        "dependencies": dependencies,
+        "missing": missing.iter().map(|path| path.to_string_lossy()).collect::<Vec<_>>(),
+        "chunks": image.chunks.iter()
+            .map(|(addr, bytes)| json!({ "addr": addr, "bytes": bytes }))
+            .collect::<Vec<_>>(),
+        "diagnostics": assembly.diagnostics.iter()
+            .map(|diagnostic| json!({ "message": diagnostic.message, "line": diagnostic.line }))
+            .collect::<Vec<_>>(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::source::MemorySources;
+    use crate::Assembler;
+
+    #[test]
+    fn test_lex_cache() {
+        let cache = LexCache::default();
+        let lines = cache.lex("loop: incr\njump loop");
+        assert_eq!(lines.len(), 2);
+        assert!(Rc::ptr_eq(&lines, &cache.lex("loop: incr\njump loop")));
+        assert_eq!(cache.len(), 1);
+
+        // An edited file is lexed again, the old version goes on the next eviction
+        let edited = cache.lex("loop: incr\nincr\njump loop");
+        assert_eq!(edited.len(), 3);
+        assert_eq!(cache.len(), 2);
+        cache.evict_unused();
+        cache.lex("loop: incr\nincr\njump loop");
+        cache.evict_unused();
+        assert_eq!(cache.len(), 1);
+        assert!(cache.lex("load rx,").first().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_build_cache() {
+        let dir = std::env::temp_dir().join(format!("tasm_cache_{}", std::process::id()));
+        let options = AssembleOptions::default();
+        let sources = |lib: &str| {
+            let mut memory = MemorySources::default();
+            memory.insert("main.tasm", ".include \"lib.tasm\"\njump VALUE");
+            memory.insert("lib.tasm", lib);
+            memory
+        };
+        let is_cached = |assembly: &Assembly| {
+            assembly
+                .diagnostics
+                .iter()
+                .any(|diagnostic| diagnostic.message.starts_with("Reusing"))
+        };
+
+        let assembler = Assembler::with_cache_dir(&dir);
+        let assembly = assembler.assemble("main.tasm", &sources(".set VALUE 5"), &options);
+        assert!(!is_cached(&assembly));
+        assert_eq!(assembly.image.unwrap().to_bytes(), vec![0b00001111, 5, 0]);
+
+        // Another run reuses the image while the files are the same
+        let assembler = Assembler::with_cache_dir(&dir);
+        let assembly = assembler.assemble("main.tasm", &sources(".set VALUE 5"), &options);
+        assert!(is_cached(&assembly));
+        assert_eq!(assembly.image.unwrap().to_bytes(), vec![0b00001111, 5, 0]);
+        assert_eq!(assembly.dependencies.len(), 2);
+
+        // Editing an included file invalidates it
+        let assembly = assembler.assemble("main.tasm", &sources(".set VALUE 7"), &options);
+        assert!(!is_cached(&assembly));
+        assert_eq!(assembly.image.unwrap().to_bytes(), vec![0b00001111, 7, 0]);
+
+        // A file created before the one found in the search directories is used instead
+        let options = AssembleOptions {
+            include_dirs: vec!["b".to_string()],
+            ..Default::default()
+        };
+        let search_sources = |shadowed: bool| {
+            let mut memory = MemorySources::default();
+            memory.insert("main.tasm", ".include \"lib.tasm\"\njump VALUE");
+            memory.insert("b/lib.tasm", ".set VALUE 5");
+            if shadowed {
+                memory.insert("lib.tasm", ".set VALUE 7");
+            }
+            memory
+        };
+        let assembly = assembler.assemble("main.tasm", &search_sources(false), &options);
+        assert_eq!(assembly.image.unwrap().to_bytes(), vec![0b00001111, 5, 0]);
+        let assembly = assembler.assemble("main.tasm", &search_sources(false), &options);
+        assert!(is_cached(&assembly));
+        let assembly = assembler.assemble("main.tasm", &search_sources(true), &options);
+        assert!(!is_cached(&assembly));
+        assert_eq!(assembly.image.unwrap().to_bytes(), vec![0b00001111, 7, 0]);
+
+        // Assemblies with errors are not kept
+        let options = AssembleOptions::default();
+        let assembly = assembler.assemble("main.tasm", &sources(""), &options);
+        assert!(assembly.has_errors());
+        let assembly = assembler.assemble("main.tasm", &sources(""), &options);
+        assert!(!is_cached(&assembly));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -11,13 +11,107 @@ pub struct Cli {
 #[derive(Subcommand)]
 pub enum Commands {
     Assemble(AssembleArgs),
+    /// Run a language server over stdio
+    Lsp,
+    /// Rewrite source files in the canonical style
+    Fmt(FmtArgs),
+    /// Check a program for common mistakes
+    Lint(LintArgs),
+    /// Combine object files written by `assemble -c`
+    Link(LinkArgs),
+    /// Bundle object files into an archive
+    Ar(ArArgs),
+    /// Build the targets of a tasm.toml manifest
+    Build(BuildArgs),
 }
 
 #[derive(Args)]
 pub struct AssembleArgs {
+    /// Source file, `-` reads stdin
     pub source: String,
+    /// Output file, `-` writes stdout
     #[arg(short, long)]
     pub output: Option<String>,
     #[arg(long)]
     pub coe: bool,
+    /// Directory searched for included files, can be repeated
+    #[arg(short = 'I', long = "include-dir", value_name = "DIR")]
+    pub include_dirs: Vec<String>,
+    /// Linker script placing the sections, defaults to tasm-link.toml when present
+    #[arg(short = 'T', long = "linker", value_name = "FILE")]
+    pub linker_script: Option<String>,
+    /// Symbol set before the first line like `.set`, NAME alone sets it to 1
+    #[arg(short = 'D', long = "define", value_name = "NAME=VALUE", value_parser = tasm::manifest::parse_define)]
+    pub defines: Vec<(String, u16)>,
+    /// Write a relocatable object file (default out.o) instead of linking
+    #[arg(short = 'c')]
+    pub compile_only: bool,
+    /// Print the files the output depends on as a Makefile rule instead of assembling
+    #[arg(short = 'M')]
+    pub print_dependencies: bool,
+    /// Also write the dependencies as a Makefile rule, named after the output with a .d extension
+    #[arg(long = "MD")]
+    pub write_dependencies: bool,
+    /// Dependency file written with -MD
+    #[arg(long = "MF", value_name = "FILE")]
+    pub dependency_file: Option<String>,
+    /// Assemble again whenever the source or a file it includes changes
+    #[arg(long, conflicts_with_all = ["compile_only", "print_dependencies"])]
+    pub watch: bool,
+    /// Directory keeping the assemblies, reused by later runs while the files don't change
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["compile_only", "print_dependencies"])]
+    pub cache_dir: Option<String>,
+}
+
+#[derive(Args)]
+pub struct LinkArgs {
+    /// Object files and archives, only the needed objects of archives are linked
+    #[arg(required = true)]
+    pub objects: Vec<String>,
+    /// Output file, `-` writes stdout
+    #[arg(short, long)]
+    pub output: Option<String>,
+    #[arg(long)]
+    pub coe: bool,
+    /// Linker script placing the sections, defaults to tasm-link.toml when present
+    #[arg(short = 'T', long = "linker", value_name = "FILE")]
+    pub linker_script: Option<String>,
+}
+
+#[derive(Args)]
+pub struct FmtArgs {
+    pub files: Vec<String>,
+    /// Only check formatting, exit with an error if a file would change
+    #[arg(long)]
+    pub check: bool,
+}
+
+#[derive(Args)]
+pub struct LintArgs {
+    pub source: String,
+    /// Rule levels configuration, defaults to tasm-lint.toml when present
+    #[arg(long)]
+    pub config: Option<String>,
+    /// Directory searched for included files, can be repeated
+    #[arg(short = 'I', long = "include-dir", value_name = "DIR")]
+    pub include_dirs: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct BuildArgs {
+    /// Targets to build, all of them by default
+    pub targets: Vec<String>,
+    /// Manifest declaring the targets, defaults to tasm.toml
+    #[arg(long)]
+    pub manifest: Option<String>,
+    /// Directory keeping the assemblies, reused by later builds while the files don't change
+    #[arg(long, value_name = "DIR")]
+    pub cache_dir: Option<String>,
+}
+
+#[derive(Args)]
+pub struct ArArgs {
+    pub output: String,
+    #[arg(required = true)]
+    pub objects: Vec<String>,
 }
@@ -0,0 +1,65 @@
+use std::path::PathBuf;
+
+/// Width after which gcc continues a rule on the next line.
+const LINE_WIDTH: usize = 75;
+
+/// Escape a file name the way gcc does in dependency files.
+fn escape(name: &str) -> String {
+    let mut escaped = String::new();
+    for c in name.chars() {
+        match c {
+            ' ' | '#' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '$' => escaped.push_str("$$"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Makefile rule stating that `target` depends on `dependencies`, in the format of `gcc -M`.
+pub fn make_rule(target: &str, dependencies: &[PathBuf]) -> String {
+    let mut rule = format!("{}:", escape(target));
+    let mut width = rule.len();
+    for dependency in dependencies {
+        let dependency = escape(&dependency.to_string_lossy());
+        if width + 1 + dependency.len() > LINE_WIDTH && width > 1 {
+            rule.push_str(" \\\n");
+            width = 0;
+        }
+        rule.push(' ');
+        rule.push_str(&dependency);
+        width += 1 + dependency.len();
+    }
+    rule.push('\n');
+    rule
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_make_rule() {
+        assert_eq!(
+            make_rule(
+                "out.bin",
+                &[PathBuf::from("main.tasm"), PathBuf::from("lib/my math.tasm")]
+            ),
+            "out.bin: main.tasm lib/my\\ math.tasm\n"
+        );
+
+        let dependencies = (0..8)
+            .map(|i| PathBuf::from(format!("lib/routines_{}.tasm", i)))
+            .collect::<Vec<_>>();
+        let rule = make_rule("out.bin", &dependencies);
+        assert_eq!(
+            rule.lines().next(),
+            Some("out.bin: lib/routines_0.tasm lib/routines_1.tasm lib/routines_2.tasm \\")
+        );
+        assert!(rule.lines().all(|line| line.len() <= LINE_WIDTH + 2));
+        assert!(rule.lines().skip(1).all(|line| line.starts_with(" lib/")));
+    }
+}
@@ -0,0 +1,47 @@
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Severity {
+    Info,
+    Error,
+}
+
+/// Message reported while assembling or linking, the CLI prints it as `INFO:` or `ERR:`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// Line of the file being parsed, starting at 0
+    pub line: Option<usize>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>, line: Option<usize>) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.into(),
+            line,
+        }
+    }
+
+    pub fn info(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Info,
+            message: message.into(),
+            line: None,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{} | at line {}", self.message, line),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use crate::parser::lexer::*;
+
+const INDENT: &str = "    ";
+const MIN_COMMENT_COLUMN: usize = 24;
+
+struct Line {
+    code: String,
+    comment: Option<String>,
+    comment_at_column_0: bool,
+}
+
+struct Statement {
+    labels: Vec<String>,
+    is_flag: bool,
+    code: String,
+}
+
+/// Tokens of a flag argument separated by spaces, except a sign and its number.
+fn normalize_argument(argument: &[Token]) -> String {
+    let mut str = String::new();
+    for (i, token) in argument.iter().enumerate() {
+        // A sign is unary unless it follows a number
+        let after_sign = i > 0
+            && matches!(argument[i - 1].kind, TokenKind::Plus | TokenKind::Minus)
+            && !(i > 1 && matches!(argument[i - 2].kind, TokenKind::Number(_)));
+        if i > 0 && !after_sign {
+            str.push(' ');
+        }
+        str.push_str(&token.kind.to_string());
+    }
+    str
+}
+
+fn normalize_statement(tokens: &[Token]) -> Statement {
+    let mut tokens = tokens;
+    let mut labels = vec![];
+    while let Ok(Some((_, length))) = Label::split_prefix(tokens) {
+        labels.push(tokens_to_string(&tokens[..length]));
+        tokens = &tokens[length..];
+    }
+
+    let Some(first) = tokens.first() else {
+        return Statement {
+            labels,
+            is_flag: false,
+            code: String::new(),
+        };
+    };
+
+    if first.kind == TokenKind::Dot {
+        let keyword = format!(
+            ".{}",
+            tokens_to_string(&tokens[1..2.min(tokens.len())]).to_lowercase()
+        );
+        // Arguments separated by commas are written without spaces, like operands
+        let arguments = tokens[2.min(tokens.len())..]
+            .split(|t| t.kind == TokenKind::Comma)
+            .map(normalize_argument)
+            .collect::<Vec<_>>()
+            .join(",");
+        return Statement {
+            labels,
+            is_flag: true,
+            code: if arguments.is_empty() {
+                keyword
+            } else {
+                format!("{} {}", keyword, arguments)
+            },
+        };
+    }
+
+    let operands = tokens[1..]
+        .iter()
+        .map(|token| {
+            let str = token.kind.to_string();
+            if REGISTERS.contains(&str.to_lowercase().as_str()) {
+                str.to_lowercase()
+            } else {
+                str
+            }
+        })
+        .collect::<String>();
+    let mnemonic = first.kind.to_string().to_lowercase();
+
+    Statement {
+        labels,
+        is_flag: false,
+        code: if operands.is_empty() {
+            mnemonic
+        } else {
+            format!("{} {}", mnemonic, operands)
+        },
+    }
+}
+
+/// Render a line: the labels of the first statement at column 0, then the
+/// statements separated by ` \ `, instructions starting at the indentation.
+fn normalize_line(tokens: &[Token]) -> String {
+    if tokens.is_empty() {
+        return String::new();
+    }
+
+    let mut statements = split_statements(tokens)
+        .into_iter()
+        .map(normalize_statement);
+    let first = statements.next().unwrap();
+    let mut code = first.labels.join(" ");
+    if !first.code.is_empty() {
+        if !first.is_flag {
+            let width = INDENT.len().max(code.len() + 1);
+            code = format!("{:width$}", code, width = width);
+        } else if !code.is_empty() {
+            code.push(' ');
+        }
+        code.push_str(&first.code);
+    }
+
+    for statement in statements {
+        code.push_str(" \\ ");
+        for label in statement.labels {
+            code.push_str(&label);
+            code.push(' ');
+        }
+        code.push_str(&statement.code);
+    }
+    code.trim_end().to_string()
+}
+
+/// Rewrite source code in the canonical style: labels and flags at column 0,
+/// instructions indented, operands without spaces and trailing comments aligned.
+pub fn format_source(source: &str) -> Result<String, String> {
+    let mut lines: Vec<Line> = vec![];
+    for (line_index, line_raw) in source.lines().enumerate() {
+        let mut tokens =
+            tokenize(line_raw).map_err(|e| format!("{} | at line {}", e, line_index))?;
+        let comment = match tokens.last().map(|t| &t.kind) {
+            Some(TokenKind::Comment(comment)) => Some(comment.to_string()),
+            _ => None,
+        };
+        if comment.is_some() {
+            tokens.pop();
+        }
+        let code = normalize_line(&tokens);
+        if let Err(err_str) = lex_line(&code) {
+            return Err(format!("{} | at line {}", err_str, line_index));
+        }
+
+        lines.push(Line {
+            code,
+            comment: comment.map(|c| c.trim_end().to_string()),
+            comment_at_column_0: line_raw.starts_with(';'),
+        });
+    }
+
+    let comment_column = lines
+        .iter()
+        .filter(|line| line.comment.is_some())
+        .map(|line| line.code.len() + 1)
+        .max()
+        .unwrap_or(0)
+        .max(MIN_COMMENT_COLUMN);
+
+    let mut output = String::new();
+    let mut previous_blank = true;
+    for line in lines {
+        let mut formatted = line.code;
+
+        if let Some(comment) = &line.comment {
+            if formatted.is_empty() {
+                if !line.comment_at_column_0 {
+                    formatted.push_str(INDENT);
+                }
+            } else {
+                formatted.push_str(&" ".repeat(comment_column - formatted.len()));
+            }
+            formatted.push_str(comment);
+        }
+
+        let is_blank = formatted.is_empty();
+        if is_blank && previous_blank {
+            continue;
+        }
+        previous_blank = is_blank;
+
+        output.push_str(&formatted);
+        output.push('\n');
+    }
+
+    while output.ends_with("\n\n") {
+        output.pop();
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let source = "; header\n\n\n.ORG   $8000\n  start:\nLOAD RX, $12   ; load\n\tload  ra ,#5\n  ; body\n        jump start ;loop\n\n";
+        let expected = "; header\n\n.org $8000\nstart:\n    load rx,$12         ; load\n    load ra,#5\n    ; body\n    jump start          ;loop\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_flags() {
+        assert_eq!(
+            format_source(
+                ".INCBIN \"font.bin\" , 0,  8\n.include \"lib.tasm\"  as lib\n.endscope\n"
+            )
+            .unwrap(),
+            ".incbin \"font.bin\",0,8\n.include \"lib.tasm\" as lib\n.endscope\n"
+        );
+        assert_eq!(
+            format_source(".set offset -1\n.org $8000+-2\n").unwrap(),
+            ".set offset -1\n.org $8000 + -2\n"
+        );
+    }
+
+    #[test]
+    fn test_format_statements() {
+        let source = "loop:  incr\\ JUMP loop ;x\nsubroutine: .label io $10\na: b:rts\n";
+        let expected = "loop: incr \\ jump loop  ;x\nsubroutine: .label io $10\na: b: rts\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+
+        let formatted = format_source("end:halt\n").unwrap();
+        assert_eq!(formatted, "end: halt\n");
+        assert_eq!(format_source("x: halt\n").unwrap(), "x:  halt\n");
+        assert_eq!(
+            format_source(":\n  @loop:jump :-\n.end:halt\n").unwrap(),
+            ":\n@loop: jump :-\n.end: halt\n"
+        );
+    }
+
+    #[test]
+    fn test_format_idempotence() {
+        let source = "start:\nload rx,$12 ; a very long comment\n.include \"./lib.tasm\" ; include\n\tshift_right ; shift\nhalt";
+        let formatted = format_source(source).unwrap();
+        assert_eq!(format_source(&formatted).unwrap(), formatted);
+        assert!(formatted.contains(".include \"./lib.tasm\"   ; include\n"));
+
+        let formatted = format_source(include_str!("../test/test.tasm")).unwrap();
+        assert_eq!(format_source(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn test_format_error() {
+        assert_eq!(
+            format_source("halt\nload rz,#5").err().unwrap(),
+            "Unknow register: rz | at line 1"
+        );
+    }
+}
@@ -0,0 +1,558 @@
+//! Assembler for Turtle Core 1. The `tasm` binary is a thin wrapper around this crate.
+//!
+//! ```
+//! let assembly = tasm::assemble_str("load rx, #5\nhalt", &tasm::AssembleOptions::default());
+//! assert!(!assembly.has_errors());
+//! assert_eq!(assembly.image.unwrap().to_bytes().len(), 3);
+//! ```
+
+pub mod cache;
+pub mod depfile;
+pub mod diagnostic;
+pub mod fmt;
+pub mod lint;
+pub mod lsp;
+pub mod manifest;
+pub mod parser;
+pub mod watch;
+
+pub use cache::{BuildCache, LexCache};
+pub use diagnostic::{Diagnostic, Severity};
+pub use parser::lexer::{
+    AddressingMode, BranchCondition, Instruction, InstructionData, InstructionLinkedData,
+    MathOperand, Opcode, Registers,
+};
+pub use parser::linker::LinkerConfig;
+pub use parser::object::{Image, Object, OutputFormat};
+pub use parser::source::{FileSystem, LayeredSources, MemorySources, SourceProvider};
+pub use parser::{IntermediateRepresentation, Location};
+
+use std::path::PathBuf;
+
+/// Name given to source code assembled with `assemble_str` in messages.
+pub const SOURCE_NAME: &str = "<source>";
+
+#[derive(Default)]
+pub struct AssembleOptions {
+    /// Directories searched for included files, after the directory of the including file
+    pub include_dirs: Vec<String>,
+    /// Symbols set before the first line of the source, like `.set`
+    pub defines: Vec<(String, u16)>,
+    pub linker_config: LinkerConfig,
+}
+
+/// Result of assembling a program, `image` is `None` if it cannot be parsed or linked.
+pub struct Assembly {
+    pub image: Option<Image>,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Files read while assembling, the root file first
+    pub dependencies: Vec<PathBuf>,
+}
+
+impl Assembly {
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics.iter().any(Diagnostic::is_error)
+    }
+}
+
+/// Assemble source code held in memory and link it alone. Included files are read
+/// from the filesystem.
+pub fn assemble_str(source: &str, options: &AssembleOptions) -> Assembly {
+    let mut memory = MemorySources::default();
+    memory.insert(SOURCE_NAME, source);
+    let sources = LayeredSources::default().push(memory).push(FileSystem);
+    assemble_sources(SOURCE_NAME, &sources, options)
+}
+
+/// Assemble a file and link it alone.
+pub fn assemble_file(path: &str, options: &AssembleOptions) -> Assembly {
+    assemble_sources(path, &FileSystem, options)
+}
+
+/// Assemble a file read from `sources`, along with every file it includes.
+pub fn assemble_sources(
+    path: &str,
+    sources: &dyn SourceProvider,
+    options: &AssembleOptions,
+) -> Assembly {
+    Assembler::default().assemble(path, sources, options)
+}
+
+/// Assembles programs reusing the work of the previous runs, keep one across the
+/// targets of a build or the rebuilds of watch mode.
+#[derive(Default)]
+pub struct Assembler {
+    lex_cache: LexCache,
+    build_cache: Option<BuildCache>,
+}
+
+impl Assembler {
+    /// Also keep the assemblies in `dir`, reused by later runs while their files don't change.
+    pub fn with_cache_dir(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            lex_cache: LexCache::default(),
+            build_cache: Some(BuildCache::new(dir)),
+        }
+    }
+
+    pub fn assemble(
+        &self,
+        path: &str,
+        sources: &dyn SourceProvider,
+        options: &AssembleOptions,
+    ) -> Assembly {
+        match &self.build_cache {
+            Some(build_cache) => build_cache.get_or_assemble(path, sources, options, |sources| {
+                self.assemble_uncached(path, sources, options)
+            }),
+            None => self.assemble_uncached(path, sources, options),
+        }
+    }
+
+    fn assemble_uncached(
+        &self,
+        path: &str,
+        sources: &dyn SourceProvider,
+        options: &AssembleOptions,
+    ) -> Assembly {
+        let mut diagnostics = vec![];
+        let representation = IntermediateRepresentation::new(
+            path,
+            &options.include_dirs,
+            &options.defines,
+            sources,
+            &self.lex_cache,
+            &mut diagnostics,
+        );
+        // Old versions of edited files are not lexed again
+        self.lex_cache.evict_unused();
+        let Some(representation) = representation else {
+            return Assembly {
+                image: None,
+                diagnostics,
+                dependencies: vec![],
+            };
+        };
+        let image = link_alone(&representation, path, options, &mut diagnostics);
+        Assembly {
+            image,
+            diagnostics,
+            dependencies: representation.dependencies().to_vec(),
+        }
+    }
+}
+
+fn link_alone(
+    representation: &IntermediateRepresentation,
+    name: &str,
+    options: &AssembleOptions,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Option<Image> {
+    let object = representation.to_object(name);
+    Object::link(&[object], &options.linker_config, diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn assemble(path: &str) -> Option<Image> {
+        assemble_file(path, &AssembleOptions::default()).image
+    }
+
+    #[test]
+    fn test_file_assembling() {
+        let intermediate_representation_opt = assemble("./test/test.tasm");
+        assert!(intermediate_representation_opt.is_some());
+
+        let intermediate_representation = intermediate_representation_opt.unwrap();
+        assert_eq!(intermediate_representation.bytes_size(), 6);
+        assert_eq!(
+            intermediate_representation.to_bytes(),
+            vec![0b00011001, 0x34, 0x12, 0b00010001, 255, 0]
+        );
+    }
+
+    #[test]
+    fn test_str_assembling() {
+        let assembly = assemble_str(
+            "start:\nload rx, #5\njump start",
+            &AssembleOptions::default(),
+        );
+        assert!(!assembly.has_errors());
+        assert_eq!(
+            assembly.image.unwrap().to_bytes(),
+            vec![0b00010001, 5, 0b00001111, 0, 0]
+        );
+
+        let assembly = assemble_str("jump missing", &AssembleOptions::default());
+        assert!(assembly.has_errors());
+        assert_eq!(
+            assembly.diagnostics[0],
+            Diagnostic::error("Label missing didn't exist!", Some(0))
+        );
+
+        // Included files can be held in memory as well
+        let mut memory = MemorySources::default();
+        memory.insert("main.tasm", ".include \"lib/halt.tasm\"\njsr stop");
+        memory.insert("lib/halt.tasm", "stop:\nhalt");
+        let assembly = assemble_sources("main.tasm", &memory, &AssembleOptions::default());
+        assert_eq!(
+            assembly.image.unwrap().to_bytes(),
+            vec![0, 0b10011111, 0, 0]
+        );
+        assert_eq!(
+            assembly.dependencies,
+            vec![PathBuf::from("main.tasm"), PathBuf::from("lib/halt.tasm")]
+        );
+        assert_eq!(
+            Image {
+                chunks: vec![(0, vec![0xab, 1])]
+            }
+            .encode(OutputFormat::Coe, 0),
+            b"memory_initialization_radix=16;\nmemory_initialization_vector=\nab,\n01;\n"
+        );
+    }
+
+    #[test]
+    fn test_defines_assembling() {
+        let options = AssembleOptions {
+            defines: vec![("TARGET".to_string(), 0x1234)],
+            ..Default::default()
+        };
+        let assembly = assemble_str("jump TARGET\n.set TARGET $10\njump TARGET", &options);
+        assert!(!assembly.has_errors());
+        assert_eq!(
+            assembly.image.unwrap().to_bytes(),
+            vec![0b00001111, 0x34, 0x12, 0b00001111, 0x10, 0]
+        );
+    }
+
+    #[test]
+    fn test_charmap_assembling() {
+        let source = ".ascii \"Hi!\\n\"\n.charmap 'A', 'Z', $01\n.charmap '!', $3F\n.ascii \"HI!\"\nload ra,#'B'\nload rb,#'b'";
+        let assembly = assemble_str(source, &AssembleOptions::default());
+        assert!(!assembly.has_errors());
+        assert_eq!(
+            assembly.image.unwrap().to_bytes(),
+            vec![b'H', b'i', b'!', b'\n', 8, 9, 0x3F, 0b00000001, 2, 0b00110001, b'b']
+        );
+
+        // Characters are mapped inside expressions as well
+        let assembly = assemble_str(
+            ".charmap 'A', 'Z', $01\nload ra,#'A' + 1\nload rb,#'z' - 'a'",
+            &AssembleOptions::default(),
+        );
+        assert_eq!(
+            assembly.image.unwrap().to_bytes(),
+            vec![0b00000001, 2, 0b00110001, 25]
+        );
+
+        let assembly = assemble_str(".ascii \"20€\"", &AssembleOptions::default());
+        assert_eq!(
+            assembly.diagnostics[0],
+            Diagnostic::error(
+                "Character '€' is not in the character map and does not fit in 8 bits",
+                Some(0)
+            )
+        );
+        let assembly = assemble_str(
+            ".charmap '€', $80\n.ascii \"20€\"",
+            &AssembleOptions::default(),
+        );
+        assert_eq!(assembly.image.unwrap().to_bytes(), vec![b'2', b'0', 0x80]);
+
+        let assembly = assemble_str(".org $fffe\n.ascii \"abcd\"", &AssembleOptions::default());
+        assert_eq!(
+            assembly.diagnostics[0],
+            Diagnostic::error("Address 0xfffe + 4 goes past $FFFF", Some(1))
+        );
+    }
+
+    #[test]
+    fn test_statements_assembling() {
+        let intermediate_representation_opt = assemble("./test/statements.tasm");
+        assert!(intermediate_representation_opt.is_some());
+
+        let intermediate_representation = intermediate_representation_opt.unwrap();
+        assert_eq!(
+            intermediate_representation.to_bytes(),
+            vec![0b00000001, 1, 0b00010110, 0b00000110, 0b00001111, 3, 0]
+        );
+    }
+
+    #[test]
+    fn test_scopes_assembling() {
+        let intermediate_representation_opt = assemble("./test/scopes.tasm");
+        assert!(intermediate_representation_opt.is_some());
+
+        let intermediate_representation = intermediate_representation_opt.unwrap();
+        assert_eq!(
+            intermediate_representation.to_bytes(),
+            vec![
+                0b00001111, 8, 0, 0b00010110, 0b00001111, 4, 0, 0b10100111, 0b10011111, 3, 0,
+                0b00000110, 0b00001111, 11, 0, 0b00001111, 15, 0
+            ]
+        );
+    }
+
+    #[test]
+    fn test_set_assembling() {
+        let intermediate_representation_opt = assemble("./test/set.tasm");
+        assert!(intermediate_representation_opt.is_some());
+
+        let intermediate_representation = intermediate_representation_opt.unwrap();
+        assert_eq!(
+            intermediate_representation.to_bytes(),
+            vec![0b00001111, 0x10, 0, 0b00001111, 0x20, 0, 0b00001111, 0x30, 0]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_labels_assembling() {
+        let assembly = assemble_str("x: halt\nx: halt", &AssembleOptions::default());
+        assert!(assembly.diagnostics.contains(&Diagnostic::error(
+            "Label x is already defined at line 0 in <source>",
+            Some(1)
+        )));
+
+        // Only `.set` can define a symbol again
+        let assembly = assemble_str(".set x $10\n.label x $20", &AssembleOptions::default());
+        assert!(assembly.diagnostics.contains(&Diagnostic::error(
+            "Label x is already defined at line 0 in <source>",
+            Some(1)
+        )));
+    }
+
+    #[test]
+    fn test_include_dirs_assembling() {
+        let options = AssembleOptions {
+            include_dirs: vec!["./test/lib".to_string()],
+            ..Default::default()
+        };
+        let intermediate_representation_opt =
+            assemble_file("./test/include_once.tasm", &options).image;
+        assert!(intermediate_representation_opt.is_some());
+
+        let intermediate_representation = intermediate_representation_opt.unwrap();
+        assert_eq!(
+            intermediate_representation.to_bytes(),
+            vec![0b00010110, 0b10100111, 0b10011111, 0, 0]
+        );
+
+        let assembly = assemble_file("./test/cycle_a.tasm", &AssembleOptions::default());
+        assert!(assembly.diagnostics.contains(&Diagnostic::error(
+            "Include cycle: ./test/cycle_a.tasm -> ./test/cycle_b.tasm -> ./test/cycle_a.tasm",
+            Some(0)
+        )));
+        assert_eq!(assembly.image.unwrap().to_bytes(), vec![0b00000110, 0]);
+    }
+
+    #[test]
+    fn test_incbin_assembling() {
+        let intermediate_representation_opt = assemble("./test/incbin.tasm");
+        assert!(intermediate_representation_opt.is_some());
+
+        let intermediate_representation = intermediate_representation_opt.unwrap();
+        assert_eq!(
+            intermediate_representation.to_bytes(),
+            vec![0b00001111, 5, 0, 0xbb, 0xcc, 0, 0xaa, 0xbb, 0xcc, 0xdd]
+        );
+
+        let assembly = assemble_str(
+            ".org $fffc\n.incbin \"test/font.bin\"",
+            &AssembleOptions::default(),
+        );
+        assert!(!assembly.has_errors());
+        let assembly = assemble_str(
+            ".org $fffe\n.incbin \"test/font.bin\"",
+            &AssembleOptions::default(),
+        );
+        assert!(assembly.diagnostics.contains(&Diagnostic::error(
+            "Address 0xfffe + 4 goes past $FFFF",
+            Some(1)
+        )));
+
+        // Included bytes cannot be written over code
+        let assembly = assemble_str(
+            ".org 0\nhalt\n.org 0\n.incbin \"test/font.bin\"",
+            &AssembleOptions::default(),
+        );
+        assert!(assembly.image.is_none());
+        assert!(assembly.diagnostics.contains(&Diagnostic::error(
+            "Bytes at 0x0000 overlap the bytes at 0x0000",
+            None
+        )));
+        let assembly = assemble_str(
+            ".org 2\n.incbin \"test/font.bin\"\n.org 4\nhalt",
+            &AssembleOptions::default(),
+        );
+        assert!(assembly.diagnostics.contains(&Diagnostic::error(
+            "Bytes at 0x0004 overlap the bytes at 0x0002",
+            None
+        )));
+    }
+
+    #[test]
+    fn test_align_assembling() {
+        let intermediate_representation_opt = assemble("./test/align.tasm");
+        assert!(intermediate_representation_opt.is_some());
+
+        let intermediate_representation = intermediate_representation_opt.unwrap();
+        assert_eq!(
+            intermediate_representation.to_bytes(),
+            vec![
+                0b00000110, 0xff, 0xff, 0xff, 0xaa, 0xbb, 0xcc, 0xdd, 0, 0, 0, 0, 0b00001111, 4, 0
+            ]
+        );
+
+        // Code can fill the memory up to $FFFF but not go past it
+        let assembly = assemble_str(".org $fff0\n.res $10", &AssembleOptions::default());
+        assert!(!assembly.has_errors());
+        let assembly = assemble_str(
+            ".org $fff0\n.align $100, $ff\nhalt",
+            &AssembleOptions::default(),
+        );
+        assert_eq!(
+            assembly.diagnostics[0],
+            Diagnostic::error("Address 0x10000 + 1 goes past $FFFF", Some(2))
+        );
+        let assembly = assemble_str(".org $fff0\n.res $20", &AssembleOptions::default());
+        assert_eq!(
+            assembly.diagnostics[0],
+            Diagnostic::error("Address 0xfff0 + 32 goes past $FFFF", Some(1))
+        );
+        let assembly = assemble_str(
+            ".section bss\n.res $ffff\n.res 2",
+            &AssembleOptions::default(),
+        );
+        assert_eq!(
+            assembly.diagnostics[0],
+            Diagnostic::error("Section bss goes past $FFFF bytes", Some(2))
+        );
+
+        // Absolute code can end at $10000 along with a page
+        let assembly = assemble_str(
+            ".org $ff00\nstart: .res $100\n.assert_page start",
+            &AssembleOptions::default(),
+        );
+        assert!(!assembly.has_errors());
+
+        // Failing `.assert_page` blocks
+        let assembly = assemble_str(
+            ".org $00fe\nstart: load ra,#1\nhalt\n.assert_page start",
+            &AssembleOptions::default(),
+        );
+        assert!(assembly.has_errors());
+        assert!(assembly.diagnostics.contains(&Diagnostic::error(
+            "Block from start at 0x00fe to 0x0100 crosses a page boundary",
+            Some(3)
+        )));
+        let assembly = assemble_str(
+            ".assert_page later\nlater: halt",
+            &AssembleOptions::default(),
+        );
+        assert!(assembly.diagnostics.contains(&Diagnostic::error(
+            "Label later should be defined before .assert_page",
+            Some(0)
+        )));
+    }
+
+    #[test]
+    fn test_sections_assembling() {
+        let intermediate_representation_opt = assemble("./test/sections.tasm");
+        assert!(intermediate_representation_opt.is_some());
+        assert_eq!(
+            intermediate_representation_opt.unwrap().to_bytes(),
+            vec![
+                0b00011001, 14, 0, 0b10011111, 10, 0, 0b00101001, 14, 0, 0, 0b00011011, 14, 0,
+                0b10100111
+            ]
+        );
+
+        let linker_script = fs::read_to_string("./test/sections.toml").unwrap();
+        let options = AssembleOptions {
+            linker_config: LinkerConfig::from_toml(&linker_script).unwrap(),
+            ..Default::default()
+        };
+        let intermediate_representation_opt = assemble_file("./test/sections.tasm", &options).image;
+        assert!(intermediate_representation_opt.is_some());
+        assert_eq!(
+            intermediate_representation_opt.unwrap().to_bytes(),
+            vec![
+                0b00011001, 0x80, 0, 0b10011111, 10, 0, 0b00101001, 0x80, 0, 0, 0b00011011, 0x80,
+                0, 0b10100111
+            ]
+        );
+
+        // A section can end at the top of the memory
+        let options = AssembleOptions {
+            linker_config: LinkerConfig::from_toml(
+                "[[memory]]\nname = \"top\"\nstart = 0xFFF0\nsize = 0x10\n\n[[section]]\nname = \"code\"\nload = \"top\"",
+            )
+            .unwrap(),
+            ..Default::default()
+        };
+        let assembly = assemble_str(".section code\n.ascii \"0123456789abcdef\"", &options);
+        assert!(!assembly.has_errors());
+        let image = assembly.image.unwrap();
+        assert_eq!(image.chunks[0].0, 0xFFF0);
+        assert_eq!(image.to_bytes(), b"0123456789abcdef");
+
+        // A page asserted at the top of the memory ends at $10000
+        let options = AssembleOptions {
+            linker_config: LinkerConfig::from_toml(
+                "[[memory]]\nname = \"top\"\nstart = 0xFF00\nsize = 0x100\n\n[[section]]\nname = \"code\"\nload = \"top\"",
+            )
+            .unwrap(),
+            ..Default::default()
+        };
+        let assembly = assemble_str(
+            ".section code\nstart: .res $100\n.assert_page start",
+            &options,
+        );
+        assert!(!assembly.has_errors());
+        let assembly = assemble_str(
+            ".section code\nstart: .res $ff\nend: .res 1\n.assert_page end\n.assert_page start",
+            &options,
+        );
+        assert!(!assembly.has_errors());
+    }
+
+    #[test]
+    fn test_objects_linking() {
+        let objects = ["./test/objects/main.tasm", "./test/objects/double.tasm"]
+            .iter()
+            .map(|path| {
+                let intermediate_representation = IntermediateRepresentation::new(
+                    path,
+                    &[],
+                    &[],
+                    &FileSystem,
+                    &LexCache::default(),
+                    &mut vec![],
+                )
+                .unwrap();
+                let object = intermediate_representation.to_object(path);
+                Object::from_json(&object.to_json()).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let image = Object::link(&objects, &LinkerConfig::default(), &mut vec![]);
+        assert!(image.is_some());
+        assert_eq!(
+            image.unwrap().to_bytes(),
+            vec![0b10011111, 6, 0, 0b00001111, 0, 0, 0b00010110, 0b10100111]
+        );
+
+        // Missing and duplicate symbols
+        let linker_config = LinkerConfig::default();
+        let mut diagnostics = vec![];
+        assert!(Object::link(&objects[..1], &linker_config, &mut diagnostics).is_none());
+        assert!(diagnostics.iter().any(Diagnostic::is_error));
+        let duplicates =
+            [&objects[1], &objects[1]].map(|object| Object::from_json(&object.to_json()).unwrap());
+        assert!(Object::link(&duplicates, &linker_config, &mut vec![]).is_none());
+    }
+}
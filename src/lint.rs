@@ -0,0 +1,705 @@
+use crate::cache::LexCache;
+use crate::parser::lexer::*;
+use crate::parser::source::{LayeredSources, MemorySources, SourceProvider};
+use crate::parser::IntermediateRepresentation;
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+
+pub const DEFAULT_CONFIG_PATH: &str = "tasm-lint.toml";
+const MAX_STACK_DEPTH: i32 = 16;
+
+pub const RULES: [&str; 7] = [
+    "unreachable-code",
+    "unused-label",
+    "push-pull-imbalance",
+    "missing-rts",
+    "carry-not-set",
+    "alias-shadowing",
+    "branch-into-instruction",
+];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+#[derive(Default)]
+pub struct LintConfig {
+    levels: HashMap<String, Level>,
+}
+
+impl LintConfig {
+    /// Read rule levels from a `[rules]` table, e.g. `unused-label = "allow"`.
+    pub fn from_toml(str: &str) -> Result<Self, String> {
+        let table = str.parse::<toml::Table>().map_err(|e| e.to_string())?;
+        let mut levels = HashMap::new();
+        if let Some(rules) = table.get("rules") {
+            let rules = rules.as_table().ok_or("[rules] should be a table")?;
+            for (rule, level) in rules.iter() {
+                if !RULES.contains(&rule.as_str()) {
+                    return Err(format!("Unknow lint rule: {}", rule));
+                }
+                let level = match level.as_str() {
+                    Some("allow") => Level::Allow,
+                    Some("warn") => Level::Warn,
+                    Some("deny") => Level::Deny,
+                    _ => return Err(format!("Level of {} should be allow, warn or deny", rule)),
+                };
+                levels.insert(rule.to_string(), level);
+            }
+        }
+        Ok(Self { levels })
+    }
+
+    pub fn level(&self, rule: &str) -> Level {
+        *self.levels.get(rule).unwrap_or(&Level::Warn)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Warning {
+    pub rule: &'static str,
+    pub level: Level,
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+enum StatementKind {
+    Instruction(Instruction),
+    Label(String),
+    Alias(String),
+    /// `.org` or `.section`, the next statement does not follow the previous one
+    Org,
+}
+
+struct Statement {
+    file: usize,
+    line: usize,
+    addr: u16,
+    /// Address the instruction branches to, once sections are placed
+    target: Option<u16>,
+    kind: StatementKind,
+}
+
+#[derive(Default)]
+struct Program {
+    files: Vec<String>,
+    statements: Vec<Statement>,
+    allows: HashMap<(usize, usize), Vec<String>>,
+    /// Labels exported with `.global`, used by other object files
+    globals: HashSet<String>,
+}
+
+impl Program {
+    /// Take the statements of a parsed program at the addresses they get without a linker script.
+    fn new(
+        representation: &IntermediateRepresentation,
+        section_starts: &HashMap<String, u16>,
+        sources: &dyn SourceProvider,
+    ) -> Self {
+        let mut program = Program {
+            globals: representation.globals().iter().cloned().collect(),
+            ..Default::default()
+        };
+        for statement in representation.statements() {
+            let file = match program.files.iter().position(|f| f == &statement.file) {
+                Some(file) => file,
+                None => {
+                    program.collect_allows(sources, &statement.file);
+                    program.files.len() - 1
+                }
+            };
+            let kind = match &statement.token_type {
+                TokenType::Instruction(instruction) => {
+                    let mut instruction = instruction.clone();
+                    if let Some(InstructionLinkedData::NotResolvedRelative(label)) =
+                        &instruction.linked_data
+                    {
+                        if let Some(name) = statement
+                            .candidates(label)
+                            .into_iter()
+                            .find(|name| representation.label(name).is_some())
+                        {
+                            instruction.linked_data =
+                                Some(InstructionLinkedData::NotResolvedRelative(name));
+                        }
+                    }
+                    StatementKind::Instruction(instruction)
+                }
+                TokenType::Label(label) if label.is_anonymous() => {
+                    StatementKind::Label(ANONYMOUS_LABEL.to_string())
+                }
+                TokenType::Label(label) => match statement.label_name(&label.name) {
+                    Ok(name) => StatementKind::Label(name),
+                    Err(_) => continue,
+                },
+                TokenType::Flag(Flag::Label((name, _)) | Flag::Set((name, _))) => {
+                    StatementKind::Alias(scoped_name(&statement.scopes, name))
+                }
+                TokenType::Flag(Flag::Org(_) | Flag::Section(_)) => StatementKind::Org,
+                TokenType::Flag(_) => continue,
+            };
+            program.statements.push(Statement {
+                file,
+                line: statement.line,
+                addr: statement.location.placed(section_starts),
+                target: representation
+                    .target(&statement.location)
+                    .filter(|_| matches!(kind, StatementKind::Instruction(_)))
+                    .map(|target| target.placed(section_starts)),
+                kind,
+            });
+        }
+        program
+    }
+
+    /// Read the `tasm-lint: allow(...)` comments of a file, a comment only line applies
+    /// its allows to the next line.
+    fn collect_allows(&mut self, sources: &dyn SourceProvider, path: &str) {
+        let allow_re = Regex::new(r"tasm-lint:\s*allow\(([a-z\-, ]+)\)").unwrap();
+        let file = self.files.len();
+        self.files.push(path.to_string());
+        let source_code = sources.read_to_string(Path::new(path)).unwrap_or_default();
+        let mut pending_allows: Vec<String> = vec![];
+        for (line_index, line_raw) in source_code.lines().enumerate() {
+            let mut allows = std::mem::take(&mut pending_allows);
+            if let Some(captures) = allow_re.captures(line_raw) {
+                allows.extend(captures[1].split(',').map(|r| r.trim().to_string()));
+            }
+            if trim_line(line_raw).is_empty() {
+                if line_raw.trim_start().starts_with(';') {
+                    pending_allows = allows;
+                }
+                continue;
+            }
+            if !allows.is_empty() {
+                self.allows.insert((file, line_index), allows);
+            }
+        }
+    }
+
+    fn instructions(&self) -> BTreeMap<u16, usize> {
+        self.statements
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| matches!(s.kind, StatementKind::Instruction(_)))
+            .map(|(index, s)| (s.addr, index))
+            .collect()
+    }
+
+    fn instruction(&self, index: usize) -> &Instruction {
+        match &self.statements[index].kind {
+            StatementKind::Instruction(instruction) => instruction,
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn branch_condition(instruction: &Instruction) -> Option<BranchCondition> {
+    match (instruction.opcode, instruction.data) {
+        (Opcode::Jump, InstructionData::BranchCondition(condition)) => Some(condition),
+        _ => None,
+    }
+}
+
+fn is_terminator(instruction: &Instruction) -> bool {
+    instruction.opcode == Opcode::Break
+        || matches!(
+            branch_condition(instruction),
+            Some(BranchCondition::NoCondition | BranchCondition::ReturnFromSubroutine)
+        )
+}
+
+struct Linter<'a> {
+    program: &'a Program,
+    config: &'a LintConfig,
+    warnings: Vec<Warning>,
+}
+
+impl Linter<'_> {
+    fn warn(&mut self, rule: &'static str, statement: &Statement, message: String) {
+        let level = self.config.level(rule);
+        let allowed = self
+            .program
+            .allows
+            .get(&(statement.file, statement.line))
+            .is_some_and(|allows| allows.iter().any(|r| r == rule));
+        if level == Level::Allow || allowed {
+            return;
+        }
+
+        let warning = Warning {
+            rule,
+            level,
+            file: self.program.files[statement.file].to_string(),
+            line: statement.line,
+            message,
+        };
+        if !self.warnings.contains(&warning) {
+            self.warnings.push(warning);
+        }
+    }
+
+    fn check_linear_flow(&mut self) {
+        let mut after_terminator = false;
+        let mut carry_known = false;
+        let mut previous_file = usize::MAX;
+        for statement in self.program.statements.iter() {
+            if statement.file != previous_file {
+                previous_file = statement.file;
+                after_terminator = false;
+                carry_known = false;
+            }
+
+            match &statement.kind {
+                StatementKind::Label(_) | StatementKind::Org => {
+                    after_terminator = false;
+                    carry_known = false;
+                }
+                StatementKind::Alias(_) => {}
+                StatementKind::Instruction(instruction) => {
+                    if after_terminator {
+                        self.warn(
+                            "unreachable-code",
+                            statement,
+                            "Instruction is unreachable, previous instruction never falls through"
+                                .to_string(),
+                        );
+                    }
+                    after_terminator = is_terminator(instruction);
+
+                    match instruction.data {
+                        InstructionData::MathOperand(MathOperand::Add | MathOperand::Sub) => {
+                            if !carry_known {
+                                self.warn(
+                                    "carry-not-set",
+                                    statement,
+                                    "Carry flag is used as input but was not set up before"
+                                        .to_string(),
+                                );
+                            }
+                            carry_known = true;
+                        }
+                        InstructionData::MathOperand(
+                            MathOperand::ShiftLeft | MathOperand::ShiftRight,
+                        )
+                        | InstructionData::BranchCondition(
+                            BranchCondition::CarryFlagClear | BranchCondition::CarryFlagSet,
+                        ) => carry_known = true,
+                        InstructionData::BranchCondition(BranchCondition::JumpToSubroutine) => {
+                            carry_known = false
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_labels(&mut self) {
+        let mut referenced: HashSet<&str> =
+            self.program.globals.iter().map(String::as_str).collect();
+        let mut code_labels: HashSet<&str> = HashSet::new();
+        for statement in self.program.statements.iter() {
+            match &statement.kind {
+                StatementKind::Instruction(instruction) => {
+                    if let Some(InstructionLinkedData::NotResolvedRelative(label)) =
+                        &instruction.linked_data
+                    {
+                        referenced.insert(label);
+                    }
+                }
+                StatementKind::Label(name) => {
+                    code_labels.insert(name);
+                }
+                _ => {}
+            }
+        }
+
+        for statement in self.program.statements.iter() {
+            match &statement.kind {
+                StatementKind::Label(name) | StatementKind::Alias(name)
+                    if !referenced.contains(name.as_str()) && name != ANONYMOUS_LABEL =>
+                {
+                    self.warn(
+                        "unused-label",
+                        statement,
+                        format!("Label {} is never used", name),
+                    );
+                }
+                _ => {}
+            }
+            // An alias with the name of a code label is a duplicate label, inside a scope
+            // it hides the code label of the outer scopes
+            if let StatementKind::Alias(name) = &statement.kind {
+                let mut scopes = name
+                    .split(NAMESPACE_SEPARATOR)
+                    .map(str::to_string)
+                    .collect::<Vec<_>>();
+                let base_name = scopes.pop().unwrap_or_default();
+                if scoped_candidates(&scopes, &base_name)
+                    .iter()
+                    .skip(1)
+                    .any(|outer| code_labels.contains(outer.as_str()))
+                {
+                    self.warn(
+                        "alias-shadowing",
+                        statement,
+                        format!(".label {} shadows a code label", name),
+                    );
+                }
+            }
+        }
+    }
+
+    fn check_branches(&mut self) {
+        let instructions = self.program.instructions();
+        let (Some(first_addr), Some(last_addr)) =
+            (instructions.keys().next(), instructions.keys().next_back())
+        else {
+            return;
+        };
+        let end_addr =
+            *last_addr as u32 + self.program.instruction(instructions[last_addr]).size as u32;
+
+        for statement in self.program.statements.iter() {
+            let StatementKind::Instruction(instruction) = &statement.kind else {
+                continue;
+            };
+            if branch_condition(instruction).is_none() {
+                continue;
+            }
+            if let Some(target) = statement.target {
+                if target >= *first_addr
+                    && (target as u32) < end_addr
+                    && !instructions.contains_key(&target)
+                {
+                    self.warn(
+                        "branch-into-instruction",
+                        statement,
+                        format!(
+                            "Branch target {:#06x} is not the start of an instruction",
+                            target
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Walk every path of each subroutine called with `jsr`, tracking the stack depth.
+    fn check_subroutines(&mut self) {
+        let instructions = self.program.instructions();
+        let program = self.program;
+        let mut checked: HashSet<u16> = HashSet::new();
+        for statement in program.statements.iter() {
+            let StatementKind::Instruction(instruction) = &statement.kind else {
+                continue;
+            };
+            if branch_condition(instruction) != Some(BranchCondition::JumpToSubroutine) {
+                continue;
+            }
+            let Some(entry) = statement.target else {
+                continue;
+            };
+            if !instructions.contains_key(&entry) || !checked.insert(entry) {
+                continue;
+            }
+
+            let mut reach_rts = false;
+            let mut escape = false;
+            let mut visited: HashSet<(u16, i32)> = HashSet::new();
+            let mut stack = vec![(entry, 0i32)];
+            while let Some((addr, depth)) = stack.pop() {
+                if !visited.insert((addr, depth)) {
+                    continue;
+                }
+                let Some(index) = instructions.get(&addr) else {
+                    escape = true;
+                    continue;
+                };
+                let current = &program.statements[*index];
+                let current_instruction = self.program.instruction(*index);
+                let next_addr = addr.wrapping_add(current_instruction.size);
+
+                let mut successors = vec![];
+                match (
+                    current_instruction.opcode,
+                    branch_condition(current_instruction),
+                ) {
+                    (Opcode::Break, _) => {}
+                    (_, Some(BranchCondition::ReturnFromSubroutine)) => {
+                        reach_rts = true;
+                        if depth != 0 {
+                            self.warn(
+                                "push-pull-imbalance",
+                                current,
+                                format!(
+                                    "Stack is unbalanced by {} at return from subroutine",
+                                    depth
+                                ),
+                            );
+                        }
+                    }
+                    (_, Some(BranchCondition::JumpToSubroutine)) => {
+                        successors.push((next_addr, depth))
+                    }
+                    (_, Some(condition)) => match current.target {
+                        Some(target) => {
+                            successors.push((target, depth));
+                            if condition != BranchCondition::NoCondition {
+                                successors.push((next_addr, depth));
+                            }
+                        }
+                        None => escape = true,
+                    },
+                    (Opcode::Push, _) => successors.push((next_addr, depth + 1)),
+                    (Opcode::Pull, _) => successors.push((next_addr, depth - 1)),
+                    _ => successors.push((next_addr, depth)),
+                }
+
+                for (successor, depth) in successors {
+                    if depth.abs() <= MAX_STACK_DEPTH {
+                        stack.push((successor, depth));
+                    }
+                }
+            }
+
+            if !reach_rts && !escape {
+                self.warn(
+                    "missing-rts",
+                    statement,
+                    format!("Subroutine at {:#06x} never returns with rts", entry),
+                );
+            }
+        }
+    }
+}
+
+/// Lint a program and every file it includes, included files are also searched in
+/// `include_dirs`. Lexing errors are returned as `Err`.
+pub fn lint_source(
+    path: &str,
+    source_code: &str,
+    include_dirs: &[String],
+    sources: &dyn SourceProvider,
+    config: &LintConfig,
+) -> Result<Vec<Warning>, Vec<String>> {
+    let mut buffer = MemorySources::default();
+    buffer.insert(path, source_code);
+    let sources = LayeredSources::default().push(buffer).push(sources);
+    let mut diagnostics = vec![];
+    let Some(representation) = IntermediateRepresentation::new(
+        path,
+        include_dirs,
+        &[],
+        &sources,
+        &LexCache::default(),
+        &mut diagnostics,
+    ) else {
+        return Err(diagnostics.into_iter().map(|d| d.message).collect());
+    };
+    let mut errors = representation
+        .errors()
+        .iter()
+        .map(|e| format!("{} | at line {} in {}", e.message, e.line, e.file))
+        .collect::<Vec<_>>();
+    let section_starts = representation
+        .section_starts()
+        .unwrap_or_else(|placement_errors| {
+            errors.extend(placement_errors);
+            HashMap::new()
+        });
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let program = Program::new(&representation, &section_starts, &sources);
+
+    let mut linter = Linter {
+        program: &program,
+        config,
+        warnings: vec![],
+    };
+    linter.check_linear_flow();
+    linter.check_labels();
+    linter.check_branches();
+    linter.check_subroutines();
+
+    let mut warnings = linter.warnings;
+    warnings.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+    Ok(warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::source::FileSystem;
+    use std::fs::read_to_string;
+
+    fn lint(source_code: &str) -> Vec<(&'static str, usize)> {
+        lint_source(
+            "test.tasm",
+            source_code,
+            &[],
+            &FileSystem,
+            &LintConfig::default(),
+        )
+        .unwrap()
+        .iter()
+        .map(|w| (w.rule, w.line))
+        .collect()
+    }
+
+    #[test]
+    fn test_unreachable_code() {
+        assert_eq!(lint("halt\nincr\n"), vec![("unreachable-code", 1)]);
+        assert_eq!(
+            lint("start:\njump start\nincr\nincr\n"),
+            vec![("unreachable-code", 2)]
+        );
+        assert_eq!(lint("start:\njump start\nend:\nincr\njump end\n"), vec![]);
+        assert_eq!(lint("start: jump start \\ end: incr \\ jump end\n"), vec![]);
+        assert_eq!(lint("a: jump @x\n@x: jump :+\n: jump a\n"), vec![]);
+        assert_eq!(
+            lint("a: jump @x\n@x: halt\nb:\n@x: halt\n"),
+            vec![
+                ("unused-label", 0),
+                ("unused-label", 2),
+                ("unused-label", 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels() {
+        assert_eq!(lint("start:\nhalt\n"), vec![("unused-label", 0)]);
+        assert_eq!(
+            lint("start: jump start\n.scope s\n.label start $8000\n.endscope\njump s::start\n"),
+            vec![("alias-shadowing", 2), ("unreachable-code", 4)]
+        );
+        assert_eq!(
+            lint("start: jump start\n.scope s\nstart: jump start\nend:\n.endscope\n"),
+            vec![("unused-label", 3)]
+        );
+    }
+
+    #[test]
+    fn test_subroutines() {
+        let source_code = "jsr sub\nhalt\nsub:\npush ra\nbcc done\npull ra\ndone:\nrts\n";
+        assert_eq!(lint(source_code), vec![("push-pull-imbalance", 7)]);
+
+        let source_code = "jsr sub\nhalt\nsub:\nincr\nhalt\n";
+        assert_eq!(lint(source_code), vec![("missing-rts", 0)]);
+
+        let source_code = "jsr sub\nhalt\nsub:\npush ra\npull ra\nrts\n";
+        assert_eq!(lint(source_code), vec![]);
+    }
+
+    #[test]
+    fn test_carry_and_branches() {
+        assert_eq!(lint("add\n"), vec![("carry-not-set", 0)]);
+        assert_eq!(lint("shift_left\nadd\nsub\n"), vec![]);
+        assert_eq!(
+            lint("load ra,#1\njump $0001\n"),
+            vec![("branch-into-instruction", 1)]
+        );
+    }
+
+    #[test]
+    fn test_sections() {
+        // The variable in bss does not end the code section
+        assert_eq!(
+            lint(".section code\nstart: incr\n.section bss\ncounter: .res 1\n.section code\n jump start\n load rx,counter\n"),
+            vec![("unreachable-code", 6)]
+        );
+        // The data section starts aligned at $0004, past the target of the jump
+        assert_eq!(
+            lint(".section code\njump $0003\n.section data\n.align 4\nhalt\n"),
+            vec![("branch-into-instruction", 1)]
+        );
+    }
+
+    #[test]
+    fn test_global_labels() {
+        assert_eq!(
+            lint(".global init\ninit: rts\nother: rts\n"),
+            vec![("unused-label", 2)]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let errors = lint_source(
+            "test.tasm",
+            "a: halt\na: halt\njump a\n",
+            &[],
+            &FileSystem,
+            &LintConfig::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            errors,
+            vec!["Label a is already defined at line 0 in test.tasm | at line 1 in test.tasm"]
+        );
+    }
+
+    #[test]
+    fn test_includes() {
+        let source_code = read_to_string("test/cycle_a.tasm").unwrap();
+        let errors = lint_source(
+            "test/cycle_a.tasm",
+            &source_code,
+            &[],
+            &FileSystem,
+            &LintConfig::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            errors,
+            vec!["Include cycle: test/cycle_a.tasm -> test/cycle_b.tasm -> test/cycle_a.tasm | at line 0 in test/cycle_b.tasm"]
+        );
+
+        let source_code = read_to_string("test/include_once.tasm").unwrap();
+        let warnings = lint_source(
+            "test/include_once.tasm",
+            &source_code,
+            &["test/lib".to_string()],
+            &FileSystem,
+            &LintConfig::default(),
+        )
+        .unwrap();
+        // The file included twice is only collected once
+        assert_eq!(
+            warnings
+                .iter()
+                .map(|w| (w.rule, w.file.as_str(), w.line))
+                .collect::<Vec<_>>(),
+            vec![("carry-not-set", "test/lib/util.tasm", 0)]
+        );
+    }
+
+    #[test]
+    fn test_allow() {
+        assert_eq!(lint("add ; tasm-lint: allow(carry-not-set)\n"), vec![]);
+        assert_eq!(
+            lint("; tasm-lint: allow(unreachable-code, carry-not-set)\nadd\nhalt\nincr\n"),
+            vec![("unreachable-code", 3)]
+        );
+
+        let config = LintConfig::from_toml(
+            "[rules]\ncarry-not-set = \"allow\"\nunreachable-code = \"deny\"",
+        )
+        .unwrap();
+        let warnings =
+            lint_source("test.tasm", "add\nhalt\nincr\n", &[], &FileSystem, &config).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].level, Level::Deny);
+
+        assert!(LintConfig::from_toml("[rules]\nunknown = \"allow\"").is_err());
+        assert!(LintConfig::from_toml("[rules]\nunused-label = \"never\"").is_err());
+    }
+}
@@ -0,0 +1,603 @@
+use crate::cache::LexCache;
+use crate::parser::lexer::*;
+use crate::parser::source::{LayeredSources, MemorySources, SourceProvider};
+use crate::parser::{IntermediateRepresentation, Statement};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Byte range in a line, converted to the UTF-16 positions of the protocol by the server.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct Span {
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// Span of `tokens` in the line of the statement, the whole statement if there are none.
+    fn of(statement: &Statement, tokens: &[Token]) -> Self {
+        let tokens = if tokens.is_empty() {
+            &statement.tokens
+        } else {
+            tokens
+        };
+        Span {
+            line: statement.line,
+            start: tokens.first().map_or(0, |t| t.span.start),
+            end: tokens.last().map_or(0, |t| t.span.end),
+        }
+    }
+
+    fn contains(&self, line: usize, character: usize) -> bool {
+        self.line == line && self.start <= character && character <= self.end
+    }
+}
+
+pub struct Definition {
+    pub name: String,
+    pub uri: String,
+    pub span: Span,
+    pub addr: u16,
+    pub alias: bool,
+}
+
+pub struct Reference {
+    pub name: String,
+    pub span: Span,
+}
+
+pub struct Diagnostic {
+    pub span: Span,
+    pub message: String,
+}
+
+#[derive(Default)]
+pub struct Analysis {
+    pub diagnostics: Vec<Diagnostic>,
+    pub definitions: Vec<Definition>,
+    pub references: Vec<Reference>,
+    /// Address and bytes of each instruction of the root file by statement
+    pub encodings: HashMap<Span, (u16, Vec<u8>)>,
+}
+
+impl Analysis {
+    /// Analyze the text of `uri`, included files are read from `sources`. Diagnostics are
+    /// the errors of the parser on the root file.
+    pub fn new(uri: &str, text: &str, sources: &dyn SourceProvider) -> Self {
+        let path = uri_to_path(uri);
+        let path_str = path.to_string_lossy().to_string();
+        let mut buffer = MemorySources::default();
+        buffer.insert(&path, text);
+        let sources = LayeredSources::default().push(buffer).push(sources);
+        let Some(representation) = IntermediateRepresentation::new(
+            &path_str,
+            &[],
+            &[],
+            &sources,
+            &LexCache::default(),
+            &mut vec![],
+        ) else {
+            return Analysis::default();
+        };
+
+        let mut analysis = Analysis::default();
+        // Sections are where they go when the file is linked alone
+        let section_starts = match representation.section_starts() {
+            Ok(section_starts) => section_starts,
+            Err(errors) => {
+                for message in errors {
+                    analysis.diagnostics.push(Diagnostic {
+                        span: Span {
+                            line: 0,
+                            start: 0,
+                            end: 0,
+                        },
+                        message,
+                    });
+                }
+                HashMap::new()
+            }
+        };
+        for error in representation
+            .errors()
+            .iter()
+            .chain(representation.page_errors(&section_starts).iter())
+            .filter(|error| error.file == path_str)
+        {
+            analysis.diagnostics.push(Diagnostic {
+                span: Span {
+                    line: error.line,
+                    start: error.span.start,
+                    end: error.span.end,
+                },
+                message: error.message.to_string(),
+            });
+        }
+
+        // References resolve to the label the parser would use, they are kept as written
+        // when the label is missing
+        let resolve = |statement: &Statement, name: &str| {
+            statement
+                .candidates(name)
+                .into_iter()
+                .find(|name| representation.label(name).is_some())
+                .unwrap_or(name.to_string())
+        };
+        for statement in representation.statements() {
+            let is_root = statement.file == path_str;
+            let statement_uri = if is_root {
+                uri.to_string()
+            } else {
+                path_to_uri(&sources.canonicalize(Path::new(&statement.file)))
+            };
+            let tokens = &statement.tokens;
+            match &statement.token_type {
+                TokenType::Instruction(instruction) if is_root => {
+                    analysis.encodings.insert(
+                        Span::of(statement, tokens),
+                        (
+                            statement.location.placed(&section_starts),
+                            instruction.to_bytes(),
+                        ),
+                    );
+                    if let Some(InstructionLinkedData::NotResolvedRelative(name)) =
+                        &instruction.linked_data
+                    {
+                        if !is_anonymous_reference(name) {
+                            let operand = tokens[1..]
+                                .rsplit(|t| t.kind == TokenKind::Comma)
+                                .next()
+                                .unwrap_or_default();
+                            analysis.references.push(Reference {
+                                name: resolve(statement, name),
+                                span: Span::of(statement, operand),
+                            });
+                        }
+                    }
+                }
+                TokenType::Flag(Flag::AssertPage(name) | Flag::Global(name)) if is_root => {
+                    analysis.references.push(Reference {
+                        name: resolve(statement, name),
+                        span: Span::of(statement, &tokens[2..]),
+                    });
+                }
+                TokenType::Flag(Flag::Label((name, addr)) | Flag::Set((name, addr))) => {
+                    analysis.definitions.push(Definition {
+                        span: Span::of(statement, &tokens[2..3]),
+                        name: scoped_name(&statement.scopes, name),
+                        uri: statement_uri,
+                        addr: *addr,
+                        alias: true,
+                    });
+                }
+                TokenType::Label(label) if !label.is_anonymous() => {
+                    if let Ok(name) = statement.label_name(&label.name) {
+                        analysis.definitions.push(Definition {
+                            span: Span::of(statement, &tokens[..tokens.len() - 1]),
+                            name,
+                            uri: statement_uri,
+                            addr: statement.location.placed(&section_starts),
+                            alias: false,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        analysis
+    }
+
+    pub fn definition(&self, name: &str) -> Option<&Definition> {
+        self.definitions.iter().find(|d| d.name == name)
+    }
+
+    /// Name of the label referenced or defined at a position of the root file,
+    /// local labels are returned qualified with their scope.
+    pub fn symbol_at(&self, uri: &str, line: usize, character: usize) -> Option<(String, Span)> {
+        self.references
+            .iter()
+            .map(|r| (&r.name, &r.span))
+            .chain(
+                self.definitions
+                    .iter()
+                    .filter(|d| d.uri == uri)
+                    .map(|d| (&d.name, &d.span)),
+            )
+            .find(|(_, span)| span.contains(line, character))
+            .map(|(name, span)| (name.to_string(), span.clone()))
+    }
+
+    /// Statement of an instruction at a position of the root file, with its address and bytes.
+    pub fn encoding_at(&self, line: usize, character: usize) -> Option<(&Span, &(u16, Vec<u8>))> {
+        self.encodings
+            .iter()
+            .find(|(span, _)| span.contains(line, character))
+    }
+}
+
+pub fn uri_to_path(uri: &str) -> PathBuf {
+    PathBuf::from(
+        uri.strip_prefix("file://")
+            .unwrap_or(uri)
+            .replace("%20", " "),
+    )
+}
+
+pub fn path_to_uri(path: &std::path::Path) -> String {
+    format!("file://{}", path.display()).replace(' ', "%20")
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Return the word under the byte offset `character` on `line`, together with its span.
+pub fn word_at(text: &str, line: usize, character: usize) -> Option<(String, Span)> {
+    let line_str = text.lines().nth(line)?;
+    let character = floor_char_boundary(line_str, character);
+    let start = line_str[..character]
+        .char_indices()
+        .rev()
+        .find(|(_, c)| !is_word_char(*c))
+        .map_or(0, |(index, c)| index + c.len_utf8());
+    let end = line_str[character..]
+        .find(|c| !is_word_char(c))
+        .map_or(line_str.len(), |index| character + index);
+
+    if start == end {
+        None
+    } else {
+        Some((line_str[start..end].to_string(), Span { line, start, end }))
+    }
+}
+
+fn floor_char_boundary(str: &str, index: usize) -> usize {
+    let mut index = index.min(str.len());
+    while !str.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+/// Byte offset in `line` of a position counted in UTF-16 code units, as sent by the client.
+pub fn byte_column(line: &str, character: usize) -> usize {
+    let mut units = 0;
+    for (index, c) in line.char_indices() {
+        if units >= character {
+            return index;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+/// Position in UTF-16 code units of a byte offset in `line`, as expected by the client.
+pub fn utf16_column(line: &str, byte: usize) -> usize {
+    line[..floor_char_boundary(line, byte)]
+        .encode_utf16()
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::source::FileSystem;
+    use std::fs::read_to_string;
+
+    const SOURCE: &str = "start:\n    load rx,#5\n    jump start\n    jump missing\n    bad\n.label io $8000\n    store ra,io\n";
+
+    #[test]
+    fn test_analysis() {
+        let analysis = Analysis::new("file:///tmp/test.tasm", SOURCE, &FileSystem);
+
+        let start = analysis.definition("start").unwrap();
+        assert_eq!(start.addr, 0);
+        assert_eq!(
+            start.span,
+            Span {
+                line: 0,
+                start: 0,
+                end: 5
+            }
+        );
+        assert!(!start.alias);
+
+        let io = analysis.definition("io").unwrap();
+        assert_eq!(io.addr, 0x8000);
+        assert!(io.alias);
+
+        assert_eq!(analysis.references.len(), 3);
+        assert_eq!(
+            analysis.references[0].span,
+            Span {
+                line: 2,
+                start: 9,
+                end: 14
+            }
+        );
+
+        assert_eq!(
+            analysis.encoding_at(1, 6),
+            Some((
+                &Span {
+                    line: 1,
+                    start: 4,
+                    end: 14
+                },
+                &(0, vec![0b00010001, 5])
+            ))
+        );
+        assert_eq!(
+            analysis.encoding_at(2, 4).unwrap().1,
+            &(2, vec![0b00001111, 0, 0])
+        );
+        assert!(analysis.encoding_at(0, 2).is_none());
+
+        // Statements separated by \ on a line have their own encoding
+        let separated = Analysis::new("file:///tmp/test.tasm", "incr \\ incr", &FileSystem);
+        assert_eq!(separated.encoding_at(0, 1).unwrap().1 .0, 0);
+        assert_eq!(separated.encoding_at(0, 9).unwrap().1 .0, 1);
+
+        let messages = analysis
+            .diagnostics
+            .iter()
+            .map(|d| (d.span.line, d.message.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![
+                (4, "Line cannot be lex"),
+                (3, "Label missing didn't exist!")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_statements_analysis() {
+        let analysis = Analysis::new(
+            "file:///tmp/test.tasm",
+            "loop: incr \\ jump loop \\ end: halt\n  incr \\ bad",
+            &FileSystem,
+        );
+
+        let end = analysis.definition("end").unwrap();
+        assert_eq!(end.addr, 4);
+        assert_eq!(
+            end.span,
+            Span {
+                line: 0,
+                start: 25,
+                end: 28
+            }
+        );
+        assert_eq!(
+            analysis.references[0].span,
+            Span {
+                line: 0,
+                start: 18,
+                end: 22
+            }
+        );
+        assert_eq!(analysis.diagnostics.len(), 1);
+        assert_eq!(
+            analysis.diagnostics[0].span,
+            Span {
+                line: 1,
+                start: 9,
+                end: 12
+            }
+        );
+    }
+
+    #[test]
+    fn test_local_labels_analysis() {
+        let uri = "file:///tmp/test.tasm";
+        let analysis = Analysis::new(
+            uri,
+            "start:\n@loop: jump @loop\nnext:\n.loop: jump .loop\n: jump :- \\ jump :+\n@x: halt",
+            &FileSystem,
+        );
+
+        let names = analysis
+            .definitions
+            .iter()
+            .map(|d| d.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec!["start", "start@loop", "next", "next@loop", "next@x"]
+        );
+        assert_eq!(analysis.references[1].name, "next@loop");
+        assert_eq!(
+            analysis.references[1].span,
+            Span {
+                line: 3,
+                start: 12,
+                end: 17
+            }
+        );
+
+        let (name, _) = analysis.symbol_at(uri, 1, 13).unwrap();
+        assert_eq!(name, "start@loop");
+        assert_eq!(analysis.definition(&name).unwrap().span.line, 1);
+
+        let messages = analysis
+            .diagnostics
+            .iter()
+            .map(|d| d.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(messages, vec!["Anonymous label reference :+ has no target"]);
+    }
+
+    #[test]
+    fn test_scopes_analysis() {
+        let path = std::fs::canonicalize("test/scopes.tasm").unwrap();
+        let text = read_to_string(&path).unwrap();
+        let analysis = Analysis::new(&path_to_uri(&path), &text, &FileSystem);
+
+        assert!(analysis.diagnostics.is_empty());
+        assert_eq!(analysis.definition("math::double").unwrap().addr, 3);
+        assert_eq!(analysis.definition("math::loop").unwrap().addr, 4);
+        assert_eq!(analysis.definition("util::loop").unwrap().addr, 11);
+        assert_eq!(analysis.definition("loop").unwrap().addr, 15);
+
+        let names = analysis
+            .references
+            .iter()
+            .map(|r| r.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["main", "math::double", "util::loop", "loop"]);
+
+        let analysis = Analysis::new(
+            "file:///tmp/test.tasm",
+            ".scope a\njump b\n.endscope\n.endscope",
+            &FileSystem,
+        );
+        let messages = analysis
+            .diagnostics
+            .iter()
+            .map(|d| (d.span.line, d.message.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![
+                (3, ".endscope without matching .scope"),
+                (1, "Label b didn't exist!")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_definitions() {
+        let analysis = Analysis::new(
+            "file:///tmp/test.tasm",
+            "start: halt\n.set x 1\n.set x 2\nstart: halt\n.label x 3",
+            &FileSystem,
+        );
+        let messages = analysis
+            .diagnostics
+            .iter()
+            .map(|d| (d.span.line, d.message.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![
+                (
+                    3,
+                    "Label start is already defined at line 0 in /tmp/test.tasm"
+                ),
+                (4, "Label x is already defined at line 2 in /tmp/test.tasm")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_page_assertions() {
+        let analysis = Analysis::new(
+            "file:///tmp/test.tasm",
+            ".org $f0\nsmall: .res $10\n.assert_page small\n.org $ff\nbig: .res 2\n.assert_page big\n.align $100\nlater:",
+            &FileSystem,
+        );
+        let messages = analysis
+            .diagnostics
+            .iter()
+            .map(|d| (d.span.line, d.message.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![(
+                5,
+                "Block from big at 0x00ff to 0x0100 crosses a page boundary"
+            )]
+        );
+        assert_eq!(analysis.definition("later").unwrap().addr, 0x200);
+    }
+
+    #[test]
+    fn test_global_extern_analysis() {
+        let analysis = Analysis::new(
+            "file:///tmp/test.tasm",
+            ".global init\n.global missing\n.extern double\ninit: jsr double\n load rx,__bss_start",
+            &FileSystem,
+        );
+        let messages = analysis
+            .diagnostics
+            .iter()
+            .map(|d| (d.span.line, d.message.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(messages, vec![(1, "Label missing didn't exist!")]);
+        assert_eq!(
+            analysis.symbol_at("file:///tmp/test.tasm", 0, 9).unwrap().0,
+            "init"
+        );
+    }
+
+    #[test]
+    fn test_parser_errors_analysis() {
+        let analysis = Analysis::new(
+            "file:///tmp/test.tasm",
+            ".include \"missing.tasm\"\nhalt\n.org $ffff\nload ra,#1\n",
+            &FileSystem,
+        );
+        let messages = analysis
+            .diagnostics
+            .iter()
+            .map(|d| (d.span.line, d.span.start, d.message.as_str()))
+            .collect::<Vec<_>>();
+        // Errors found by the parser are reported on the statement they are about
+        assert_eq!(
+            messages,
+            vec![
+                (0, 0, "Path does not exist: /tmp/missing.tasm"),
+                (3, 0, "Address 0xffff + 2 goes past $FFFF")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sections_analysis() {
+        let analysis = Analysis::new(
+            "file:///tmp/test.tasm",
+            ".section code\nstart: jump start\n.section bss\ncounter: .res 1\n.section code\nnext: incr\n.assert_page counter",
+            &FileSystem,
+        );
+        assert_eq!(analysis.definition("start").unwrap().addr, 0);
+        assert_eq!(analysis.definition("next").unwrap().addr, 3);
+        assert_eq!(analysis.definition("counter").unwrap().addr, 4);
+        assert_eq!(analysis.encoding_at(5, 6).unwrap().1 .0, 3);
+        assert_eq!(
+            analysis.diagnostics[0].message,
+            "Label counter should be in the same section as .assert_page"
+        );
+
+        // A section starts aligned like its .align
+        let analysis = Analysis::new(
+            "file:///tmp/test.tasm",
+            ".section code\nhalt\n.section data\n.align 4\ntable:",
+            &FileSystem,
+        );
+        assert_eq!(analysis.definition("table").unwrap().addr, 4);
+    }
+
+    #[test]
+    fn test_word_helpers() {
+        let (word, span) = word_at(SOURCE, 2, 11).unwrap();
+        assert_eq!(word, "start");
+        assert_eq!(
+            span,
+            Span {
+                line: 2,
+                start: 9,
+                end: 14
+            }
+        );
+        assert!(word_at(SOURCE, 1, 0).is_none());
+
+        // Columns of the protocol count UTF-16 code units, spans count bytes
+        let line = "; é𝄞\tjump x";
+        assert_eq!(byte_column(line, 6), 9);
+        assert_eq!(utf16_column(line, 9), 6);
+        assert_eq!(word_at(line, 0, 11).unwrap().1.start, 9);
+    }
+}
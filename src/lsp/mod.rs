@@ -0,0 +1,420 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufReader, Write};
+
+mod analysis;
+mod transport;
+use crate::parser::lexer::{FLAG_KEYWORDS, MNEMONICS, REGISTERS};
+use crate::parser::source::{FileSystem, LayeredSources, MemorySources};
+use analysis::*;
+use transport::*;
+
+const TEXT_DOCUMENT_SYNC_FULL: u8 = 1;
+const SEVERITY_ERROR: u8 = 1;
+const COMPLETION_KIND_VARIABLE: u8 = 6;
+const COMPLETION_KIND_KEYWORD: u8 = 14;
+const COMPLETION_KIND_REFERENCE: u8 = 18;
+const SYMBOL_KIND_FUNCTION: u8 = 12;
+const SYMBOL_KIND_CONSTANT: u8 = 14;
+const METHOD_NOT_FOUND: i32 = -32601;
+
+pub fn run() {
+    let mut reader = BufReader::new(std::io::stdin().lock());
+    let mut writer = std::io::stdout().lock();
+    let mut server = Server::default();
+
+    while let Some(message) = read_message(&mut reader) {
+        if message["method"] == "exit" {
+            break;
+        }
+        for response in server.handle(&message) {
+            if write_message(&mut writer, &response).is_err() {
+                return;
+            }
+        }
+    }
+    writer.flush().ok();
+}
+
+#[derive(Default)]
+pub struct Server {
+    documents: HashMap<String, String>,
+}
+
+impl Server {
+    pub fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let id = &message["id"];
+
+        let result = match method {
+            "initialize" => Some(json!({
+                "capabilities": {
+                    "textDocumentSync": TEXT_DOCUMENT_SYNC_FULL,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": { "triggerCharacters": [".", ","] },
+                    "documentSymbolProvider": true,
+                },
+                "serverInfo": { "name": "tasm", "version": env!("CARGO_PKG_VERSION") },
+            })),
+            "shutdown" => Some(Value::Null),
+            "textDocument/didOpen" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.documents.insert(uri.to_string(), text.to_string());
+                return vec![self.publish_diagnostics(uri)];
+            }
+            "textDocument/didChange" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                if let Some(change) = params["contentChanges"].as_array().and_then(|c| c.last()) {
+                    let text = change["text"].as_str().unwrap_or_default();
+                    self.documents.insert(uri.to_string(), text.to_string());
+                }
+                return vec![self.publish_diagnostics(uri)];
+            }
+            "textDocument/didClose" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                self.documents.remove(uri);
+                return vec![json!({
+                    "jsonrpc": "2.0",
+                    "method": "textDocument/publishDiagnostics",
+                    "params": { "uri": uri, "diagnostics": [] },
+                })];
+            }
+            "textDocument/definition" => Some(self.definition(params)),
+            "textDocument/references" => Some(self.references(params)),
+            "textDocument/hover" => Some(self.hover(params)),
+            "textDocument/completion" => Some(self.completion(params)),
+            "textDocument/documentSymbol" => Some(self.document_symbols(params)),
+            _ => None,
+        };
+
+        if id.is_null() {
+            return vec![];
+        }
+        match result {
+            Some(result) => vec![json!({ "jsonrpc": "2.0", "id": id, "result": result })],
+            None => vec![json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": METHOD_NOT_FOUND, "message": format!("Unknow method: {}", method) },
+            })],
+        }
+    }
+
+    /// Analyze a document, included files that are open are read from their unsaved text.
+    fn analyze(&self, uri: &str, text: &str) -> Analysis {
+        let mut buffers = MemorySources::default();
+        for (uri, text) in self.documents.iter() {
+            buffers.insert(uri_to_path(uri), text.as_str());
+        }
+        let sources = LayeredSources::default().push(buffers).push(FileSystem);
+        Analysis::new(uri, text, &sources)
+    }
+
+    fn document(&self, params: &Value) -> Option<(&str, &str)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let (uri, text) = self.documents.get_key_value(uri)?;
+        Some((uri.as_str(), text.as_str()))
+    }
+
+    /// Text of a line of a document, read from disk when it isn't open.
+    fn line_text(&self, uri: &str, line: usize) -> String {
+        let text = match self.documents.get(uri) {
+            Some(text) => text.to_string(),
+            None => std::fs::read_to_string(uri_to_path(uri)).unwrap_or_default(),
+        };
+        text.lines().nth(line).unwrap_or_default().to_string()
+    }
+
+    /// Position of the request with its character converted from UTF-16 to a byte offset.
+    fn position(&self, params: &Value) -> Option<(usize, usize)> {
+        let (uri, _) = self.document(params)?;
+        let line = params["position"]["line"].as_u64()? as usize;
+        let character = params["position"]["character"].as_u64()? as usize;
+        Some((line, byte_column(&self.line_text(uri, line), character)))
+    }
+
+    /// Range of a span of `uri` with its characters converted to UTF-16.
+    fn range(&self, uri: &str, span: &Span) -> Value {
+        let line = self.line_text(uri, span.line);
+        json!({
+            "start": { "line": span.line, "character": utf16_column(&line, span.start) },
+            "end": { "line": span.line, "character": utf16_column(&line, span.end) },
+        })
+    }
+
+    fn symbol_at_position(&self, analysis: &Analysis, params: &Value) -> Option<(String, Span)> {
+        let (uri, text) = self.document(params)?;
+        let (line, character) = self.position(params)?;
+        analysis
+            .symbol_at(uri, line, character)
+            .or_else(|| word_at(text, line, character))
+    }
+
+    fn publish_diagnostics(&self, uri: &str) -> Value {
+        let text = self.documents.get(uri).map_or("", |t| t.as_str());
+        let analysis = self.analyze(uri, text);
+        let diagnostics = analysis
+            .diagnostics
+            .iter()
+            .map(|d| {
+                json!({
+                    "range": self.range(uri, &d.span),
+                    "severity": SEVERITY_ERROR,
+                    "source": "tasm",
+                    "message": d.message,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        })
+    }
+
+    fn definition(&self, params: &Value) -> Value {
+        let Some((uri, text)) = self.document(params) else {
+            return Value::Null;
+        };
+        let analysis = self.analyze(uri, text);
+        let Some((word, _)) = self.symbol_at_position(&analysis, params) else {
+            return Value::Null;
+        };
+
+        match analysis.definition(&word) {
+            Some(definition) => {
+                json!({ "uri": definition.uri, "range": self.range(&definition.uri, &definition.span) })
+            }
+            None => Value::Null,
+        }
+    }
+
+    fn references(&self, params: &Value) -> Value {
+        let Some((uri, text)) = self.document(params) else {
+            return Value::Null;
+        };
+        let analysis = self.analyze(uri, text);
+        let Some((word, _)) = self.symbol_at_position(&analysis, params) else {
+            return Value::Null;
+        };
+
+        let mut locations = vec![];
+        if params["context"]["includeDeclaration"]
+            .as_bool()
+            .unwrap_or(true)
+        {
+            if let Some(definition) = analysis.definition(&word) {
+                locations.push(json!({ "uri": definition.uri, "range": self.range(&definition.uri, &definition.span) }));
+            }
+        }
+        for reference in analysis.references.iter().filter(|r| r.name == word) {
+            locations.push(json!({ "uri": uri, "range": self.range(uri, &reference.span) }));
+        }
+
+        Value::Array(locations)
+    }
+
+    fn hover(&self, params: &Value) -> Value {
+        let Some((uri, text)) = self.document(params) else {
+            return Value::Null;
+        };
+        let analysis = self.analyze(uri, text);
+        let Some((word, span)) = self.symbol_at_position(&analysis, params) else {
+            return Value::Null;
+        };
+
+        let contents = if let Some(definition) = analysis.definition(&word) {
+            let mut contents = format!("**{}**: `${:04x}`", definition.name, definition.addr);
+            if definition.alias {
+                contents += " (alias)";
+            } else if let Some((_, bytes)) = analysis
+                .encodings
+                .values()
+                .find(|(addr, _)| *addr == definition.addr)
+            {
+                contents += &format!("\n\n`{}`", format_bytes(bytes));
+            }
+            contents
+        } else if let Some((_, (addr, bytes))) = self
+            .position(params)
+            .and_then(|(line, character)| analysis.encoding_at(line, character))
+        {
+            format!("`${:04x}`: `{}`", addr, format_bytes(bytes))
+        } else {
+            return Value::Null;
+        };
+
+        json!({
+            "contents": { "kind": "markdown", "value": contents },
+            "range": self.range(uri, &span),
+        })
+    }
+
+    fn completion(&self, params: &Value) -> Value {
+        let mut items = vec![];
+        for mnemonic in MNEMONICS {
+            items.push(json!({ "label": mnemonic, "kind": COMPLETION_KIND_KEYWORD }));
+        }
+        for register in REGISTERS {
+            items.push(json!({ "label": register, "kind": COMPLETION_KIND_VARIABLE }));
+        }
+        for flag in FLAG_KEYWORDS {
+            items.push(json!({ "label": format!(".{}", flag), "kind": COMPLETION_KIND_KEYWORD }));
+        }
+        if let Some((uri, text)) = self.document(params) {
+            for definition in self.analyze(uri, text).definitions {
+                items.push(json!({
+                    "label": definition.name,
+                    "kind": COMPLETION_KIND_REFERENCE,
+                    "detail": format!("${:04x}", definition.addr),
+                }));
+            }
+        }
+
+        Value::Array(items)
+    }
+
+    fn document_symbols(&self, params: &Value) -> Value {
+        let Some((uri, text)) = self.document(params) else {
+            return Value::Null;
+        };
+
+        let symbols = self
+            .analyze(uri, text)
+            .definitions
+            .iter()
+            .filter(|d| d.uri == uri)
+            .map(|d| {
+                json!({
+                    "name": d.name,
+                    "detail": format!("${:04x}", d.addr),
+                    "kind": if d.alias { SYMBOL_KIND_CONSTANT } else { SYMBOL_KIND_FUNCTION },
+                    "range": self.range(uri, &d.span),
+                    "selectionRange": self.range(uri, &d.span),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        Value::Array(symbols)
+    }
+}
+
+fn format_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "file:///tmp/lsp_test.tasm";
+
+    fn open(server: &mut Server, text: &str) -> Vec<Value> {
+        server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": URI, "languageId": "tasm", "version": 1, "text": text } },
+        }))
+    }
+
+    fn request(server: &mut Server, method: &str, line: usize, character: usize) -> Value {
+        let mut responses = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "id": 7,
+            "method": method,
+            "params": {
+                "textDocument": { "uri": URI },
+                "position": { "line": line, "character": character },
+                "context": { "includeDeclaration": true },
+            },
+        }));
+        assert_eq!(responses.len(), 1);
+        responses.remove(0)["result"].take()
+    }
+
+    #[test]
+    fn test_session() {
+        let mut server = Server::default();
+        let init = server
+            .handle(&json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }));
+        assert_eq!(init[0]["result"]["capabilities"]["hoverProvider"], true);
+
+        let notifications = open(
+            &mut server,
+            "loop:\n    load rx,#1\n    jump loop\n    jump nowhere\n",
+        );
+        assert_eq!(
+            notifications[0]["method"],
+            "textDocument/publishDiagnostics"
+        );
+        let diagnostics = notifications[0]["params"]["diagnostics"]
+            .as_array()
+            .unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0]["range"]["start"]["line"], 3);
+
+        let definition = request(&mut server, "textDocument/definition", 2, 10);
+        assert_eq!(definition["uri"], URI);
+        assert_eq!(definition["range"]["start"]["line"], 0);
+
+        let references = request(&mut server, "textDocument/references", 0, 1);
+        assert_eq!(references.as_array().unwrap().len(), 2);
+
+        let hover = request(&mut server, "textDocument/hover", 2, 10);
+        assert_eq!(hover["contents"]["value"], "**loop**: `$0000`\n\n`11 01`");
+
+        let hover = request(&mut server, "textDocument/hover", 1, 5);
+        assert_eq!(hover["contents"]["value"], "`$0000`: `11 01`");
+
+        let completion = request(&mut server, "textDocument/completion", 1, 0);
+        let labels = completion
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["label"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert!(labels.contains(&"shift_left".to_string()));
+        assert!(labels.contains(&"rb".to_string()));
+        assert!(labels.contains(&".include".to_string()));
+        assert!(labels.contains(&"loop".to_string()));
+
+        let symbols = request(&mut server, "textDocument/documentSymbol", 0, 0);
+        assert_eq!(symbols[0]["name"], "loop");
+
+        let unknown =
+            server.handle(&json!({ "jsonrpc": "2.0", "id": 9, "method": "unknown/method" }));
+        assert_eq!(unknown[0]["error"]["code"], METHOD_NOT_FOUND);
+
+        let shutdown = server.handle(&json!({ "jsonrpc": "2.0", "id": 10, "method": "shutdown" }));
+        assert!(shutdown[0]["result"].is_null());
+    }
+
+    #[test]
+    fn test_unsaved_include() {
+        let mut server = Server::default();
+        server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": "file:///tmp/lsp_unsaved_lib.tasm", "text": "double: rts" } },
+        }));
+
+        // The included file only exists as an open buffer
+        let notifications = open(
+            &mut server,
+            ".include \"lsp_unsaved_lib.tasm\"\njsr double\n",
+        );
+        let diagnostics = notifications[0]["params"]["diagnostics"]
+            .as_array()
+            .unwrap();
+        assert!(diagnostics.is_empty());
+    }
+}
@@ -0,0 +1,49 @@
+use serde_json::Value;
+use std::io::{BufRead, Write};
+
+pub fn read_message<R: BufRead>(reader: &mut R) -> Option<Value> {
+    let mut content_length: Option<usize> = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let mut body = vec![0u8; content_length?];
+    reader.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+pub fn write_message<W: Write>(writer: &mut W, message: &Value) -> std::io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_message_round_trip() {
+        let message = json!({"jsonrpc": "2.0", "id": 1, "method": "shutdown"});
+        let mut buffer: Vec<u8> = Vec::new();
+        write_message(&mut buffer, &message).unwrap();
+        assert!(buffer.starts_with(b"Content-Length: "));
+
+        let mut reader = std::io::BufReader::new(buffer.as_slice());
+        assert_eq!(read_message(&mut reader), Some(message));
+        assert_eq!(read_message(&mut reader), None);
+    }
+}
@@ -1,81 +1,475 @@
 use clap::Parser;
 use std::fs;
-use std::io::prelude::*;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tasm::{fmt, lint, lsp, parser, Diagnostic, LayeredSources, MemorySources, Severity};
 
 mod cli;
 use cli::*;
-mod parser;
 
 const DEFAULT_OUTPUT_NAME: &str = "out";
+/// Path standing for stdin as a source and for stdout as an output.
+const STDIO_PATH: &str = "-";
+/// Name of the source read from stdin in messages.
+const STDIN_NAME: &str = "<stdin>";
+/// Delay between two checks of the watched files.
+const WATCH_INTERVAL: Duration = Duration::from_millis(200);
 
 fn main() {
-    let cli = Cli::parse();
+    let cli = Cli::parse_from(std::env::args().map(gcc_dependency_option));
 
     match cli.command {
         Commands::Assemble(args) => {
-            if let Some(intermediate_representation) =
-                parser::IntermediateRepresentation::new(&args.source)
-            {
-                if args.coe {
-                    let output_file_path = args
-                        .output
-                        .unwrap_or(DEFAULT_OUTPUT_NAME.to_string() + ".coe");
-                    println!("INFO: Writing coe output to: {}", output_file_path);
-
-                    let mut output_file =
-                        std::io::LineWriter::new(fs::File::create(output_file_path).unwrap());
-                    output_file
-                        .write_all(
-                            b"memory_initialization_radix=16;\nmemory_initialization_vector=\n",
-                        )
-                        .ok()
-                        .unwrap();
-                    let file_bytes = intermediate_representation.to_bytes();
-                    for (idx, byte) in file_bytes.iter().enumerate() {
-                        if idx == file_bytes.len() - 1 {
-                            output_file
-                                .write_all(&format!("{:#04x};\n", byte).as_bytes()[2..])
-                                .ok()
-                                .unwrap();
-                        } else {
-                            output_file
-                                .write_all(&format!("{:#04x},\n", byte).as_bytes()[2..])
-                                .ok()
-                                .unwrap();
+            let stdout_output =
+                args.output.as_deref() == Some(STDIO_PATH) || args.print_dependencies;
+            let (source_name, sources) = source_provider(&args.source);
+            let extension = if args.compile_only {
+                "o"
+            } else if args.coe {
+                "coe"
+            } else {
+                "bin"
+            };
+            let output_path = args
+                .output
+                .clone()
+                .unwrap_or(format!("{}.{}", DEFAULT_OUTPUT_NAME, extension));
+            if args.compile_only || args.print_dependencies {
+                let mut diagnostics = vec![];
+                let intermediate_representation_opt = tasm::IntermediateRepresentation::new(
+                    &source_name,
+                    &args.include_dirs,
+                    &args.defines,
+                    &sources,
+                    &tasm::LexCache::default(),
+                    &mut diagnostics,
+                );
+                print_diagnostics(&diagnostics, stdout_output);
+                let Some(intermediate_representation) = intermediate_representation_opt else {
+                    eprintln!("ERR: Cannot parse source code!");
+                    std::process::exit(1);
+                };
+                let dependencies = intermediate_representation.dependencies();
+                if args.print_dependencies {
+                    print!("{}", dependency_rule(&output_path, dependencies));
+                    return;
+                }
+                // Make would take the object and its dependency file as up to date
+                if diagnostics.iter().any(Diagnostic::is_error) {
+                    eprintln!("ERR: Output not written, fix the errors to assemble");
+                    std::process::exit(1);
+                }
+                let object = intermediate_representation.to_object(&source_name);
+                write_output(
+                    Some(output_path.clone()),
+                    "object",
+                    "o",
+                    object.to_json().as_bytes(),
+                );
+                if args.write_dependencies {
+                    write_dependency_file(&args, &output_path, dependencies);
+                }
+                return;
+            }
+
+            let options = tasm::AssembleOptions {
+                include_dirs: args.include_dirs.clone(),
+                defines: args.defines.clone(),
+                linker_config: read_linker_config(args.linker_script.clone()),
+            };
+            // Kept across the rebuilds of watch mode
+            let assembler = assembler(&args.cache_dir);
+            let assemble = |sources: &dyn tasm::SourceProvider| {
+                let assembly = assembler.assemble(&source_name, sources, &options);
+                print_diagnostics(&assembly.diagnostics, stdout_output);
+                // While watching, a failed attempt keeps the last good output
+                match assembly.image {
+                    Some(_) if args.watch && assembly.has_errors() => {
+                        eprintln!("ERR: Output not written, fix the errors to reassemble")
+                    }
+                    // A broken image would be taken as good by the program it is loaded in,
+                    // and by make through its dependency file
+                    Some(_) if assembly.has_errors() => {
+                        eprintln!("ERR: Output not written, fix the errors to assemble");
+                        std::process::exit(1);
+                    }
+                    Some(image) => {
+                        write_image(&image, Some(output_path.clone()), args.coe);
+                        if args.write_dependencies {
+                            write_dependency_file(&args, &output_path, &assembly.dependencies);
                         }
                     }
-                } else {
-                    let output_file_path = args
-                        .output
-                        .unwrap_or(DEFAULT_OUTPUT_NAME.to_string() + ".bin");
-                    println!("INFO: Writing bin output to: {}", output_file_path);
-                    let mut output_file = fs::File::create(output_file_path).unwrap();
-                    output_file
-                        .write_all(intermediate_representation.to_bytes().as_slice())
-                        .unwrap();
+                    None if args.watch => eprintln!("ERR: Cannot parse source code!"),
+                    None => {
+                        eprintln!("ERR: Cannot parse source code!");
+                        std::process::exit(1);
+                    }
                 }
+            };
+            if !args.watch {
+                assemble(&sources);
+                return;
+            }
+
+            if args.source == STDIO_PATH {
+                eprintln!("ERR: Cannot watch a source read from stdin");
+                std::process::exit(1);
+            }
+            loop {
+                // Unresolved includes are watched too, creating them triggers a rebuild
+                let stamping = tasm::watch::StampingSources::new(&sources);
+                assemble(&stamping);
+                let watcher = stamping.into_watcher();
+                println!("INFO: Watching {} files", watcher.len());
+                for path in watcher.wait(WATCH_INTERVAL) {
+                    println!("INFO: {} changed", path.display());
+                }
+            }
+        }
+        Commands::Link(args) => {
+            let mut objects = vec![];
+            let mut archives = vec![];
+            for object_path in args.objects.iter() {
+                let input = fs::read_to_string(object_path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|str| match parser::archive::Archive::from_json(&str)? {
+                        Some(archive) => {
+                            archives.push(archive);
+                            Ok(())
+                        }
+                        None => {
+                            objects.push(tasm::Object::from_json(&str)?);
+                            Ok(())
+                        }
+                    });
+                if let Err(err_str) = input {
+                    eprintln!("ERR: {} | in file {}", err_str, object_path);
+                    std::process::exit(1);
+                }
+            }
+            let mut diagnostics = vec![];
+            parser::archive::pull_objects(&mut objects, archives, &mut diagnostics);
+
+            let linker_config = read_linker_config(args.linker_script);
+            let image_opt = tasm::Object::link(&objects, &linker_config, &mut diagnostics);
+            print_diagnostics(&diagnostics, args.output.as_deref() == Some(STDIO_PATH));
+            if let Some(image) = image_opt {
+                write_image(&image, args.output, args.coe);
             } else {
-                eprintln!("ERR: Cannot parse source code!");
+                eprintln!("ERR: Cannot link object files!");
+                std::process::exit(1);
+            }
+        }
+        Commands::Ar(args) => {
+            let mut objects = vec![];
+            for object_path in args.objects.iter() {
+                let object = fs::read_to_string(object_path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|str| tasm::Object::from_json(&str));
+                match object {
+                    Ok(object) => objects.push(object),
+                    Err(err_str) => {
+                        eprintln!("ERR: {} | in file {}", err_str, object_path);
+                        std::process::exit(1);
+                    }
+                }
+            }
+
+            match parser::archive::Archive::new(objects) {
+                Ok(archive) => {
+                    println!("INFO: Writing archive to: {}", args.output);
+                    fs::write(&args.output, archive.to_json()).unwrap();
+                }
+                Err(err_str) => {
+                    eprintln!("ERR: {}", err_str);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Build(args) => {
+            let manifest_path = args
+                .manifest
+                .unwrap_or(tasm::manifest::DEFAULT_MANIFEST_PATH.to_string());
+            let targets = fs::read_to_string(&manifest_path)
+                .map_err(|e| e.to_string())
+                .and_then(|str| tasm::manifest::Manifest::from_toml(&str))
+                .and_then(|manifest| manifest.select(&args.targets));
+            let targets = match targets {
+                Ok(targets) => targets,
+                Err(err_str) => {
+                    eprintln!("ERR: {} | in file {}", err_str, manifest_path);
+                    std::process::exit(1);
+                }
+            };
+
+            // Paths of the manifest are relative to its directory
+            let manifest_dir = Path::new(&manifest_path)
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default();
+            let relative = |path: &str| manifest_dir.join(path).display().to_string();
+            let assembler = assembler(&args.cache_dir);
+            let mut success = true;
+            for target in targets {
+                println!("INFO: Building target {}", target.name);
+                let options = tasm::AssembleOptions {
+                    include_dirs: target
+                        .include_dirs
+                        .iter()
+                        .map(|dir| relative(dir))
+                        .collect(),
+                    defines: target.defines,
+                    linker_config: target.linker_config,
+                };
+                let assembly =
+                    assembler.assemble(&relative(&target.entry), &tasm::FileSystem, &options);
+                print_diagnostics(&assembly.diagnostics, false);
+                let has_errors = assembly.has_errors();
+                let Some(image) = assembly.image.filter(|_| !has_errors) else {
+                    eprintln!("ERR: Cannot build target {}", target.name);
+                    success = false;
+                    continue;
+                };
+                for output in target.outputs {
+                    let output_path = relative(&output.path);
+                    if let Some(dir) = Path::new(&output_path).parent() {
+                        fs::create_dir_all(dir).unwrap();
+                    }
+                    println!(
+                        "INFO: Writing {} output to: {}",
+                        output.format.extension(),
+                        output_path
+                    );
+                    fs::write(output_path, image.encode(output.format, target.fill)).unwrap();
+                }
+            }
+
+            if !success {
+                std::process::exit(1);
+            }
+        }
+        Commands::Lsp => lsp::run(),
+        Commands::Fmt(args) => {
+            let mut success = true;
+            for file_path in args.files.iter() {
+                let source_code = match fs::read_to_string(file_path) {
+                    Ok(source_code) => source_code,
+                    Err(err) => {
+                        eprintln!("ERR: Cannot read {}: {}", file_path, err);
+                        success = false;
+                        continue;
+                    }
+                };
+
+                match fmt::format_source(&source_code) {
+                    Ok(formatted) if formatted == source_code => {}
+                    Ok(formatted) => {
+                        if args.check {
+                            eprintln!("ERR: File is not formatted: {}", file_path);
+                            success = false;
+                        } else {
+                            println!("INFO: Formatting {}", file_path);
+                            fs::write(file_path, formatted).unwrap();
+                        }
+                    }
+                    Err(err_str) => {
+                        eprintln!("ERR: {} | in file {}", err_str, file_path);
+                        success = false;
+                    }
+                }
+            }
+
+            if !success {
+                std::process::exit(1);
+            }
+        }
+        Commands::Lint(args) => {
+            let config_path = args.config.or_else(|| {
+                fs::exists(lint::DEFAULT_CONFIG_PATH)
+                    .unwrap_or(false)
+                    .then(|| lint::DEFAULT_CONFIG_PATH.to_string())
+            });
+            let config = match config_path {
+                Some(config_path) => {
+                    let config = fs::read_to_string(&config_path)
+                        .map_err(|e| e.to_string())
+                        .and_then(|str| lint::LintConfig::from_toml(&str));
+                    match config {
+                        Ok(config) => config,
+                        Err(err_str) => {
+                            eprintln!("ERR: {} | in file {}", err_str, config_path);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                None => lint::LintConfig::default(),
+            };
+
+            let Ok(source_code) = fs::read_to_string(&args.source) else {
+                eprintln!("ERR: Path does not exist: {}", args.source);
+                std::process::exit(1);
+            };
+            match lint::lint_source(
+                &args.source,
+                &source_code,
+                &args.include_dirs,
+                &tasm::FileSystem,
+                &config,
+            ) {
+                Ok(warnings) => {
+                    let mut denied = false;
+                    for warning in warnings.iter() {
+                        let prefix = if warning.level == lint::Level::Deny {
+                            denied = true;
+                            "ERR"
+                        } else {
+                            "WARN"
+                        };
+                        eprintln!(
+                            "{}: {} [{}] | at line {} in {}",
+                            prefix, warning.message, warning.rule, warning.line, warning.file
+                        );
+                    }
+                    if denied {
+                        std::process::exit(1);
+                    }
+                }
+                Err(errors) => {
+                    for err_str in errors {
+                        eprintln!("ERR: {}", err_str);
+                    }
+                    std::process::exit(1);
+                }
             }
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_file_assembling() {
-        let intermediate_representation_opt =
-            parser::IntermediateRepresentation::new("./test/test.tasm");
-        assert!(intermediate_representation_opt.is_some());
-
-        let intermediate_representation = intermediate_representation_opt.unwrap();
-        assert_eq!(intermediate_representation.bytes_size(), 6);
-        assert_eq!(
-            intermediate_representation.to_bytes(),
-            vec![0b00011001, 0x34, 0x12, 0b00010001, 255, 0]
-        );
+/// Read the linker script given on the command line, or tasm-link.toml when present.
+fn read_linker_config(linker_script_path: Option<String>) -> tasm::LinkerConfig {
+    let linker_script_path = linker_script_path.or_else(|| {
+        fs::exists(parser::linker::DEFAULT_LINKER_SCRIPT_PATH)
+            .unwrap_or(false)
+            .then(|| parser::linker::DEFAULT_LINKER_SCRIPT_PATH.to_string())
+    });
+    match linker_script_path {
+        Some(linker_script_path) => {
+            let linker_config = fs::read_to_string(&linker_script_path)
+                .map_err(|e| e.to_string())
+                .and_then(|str| tasm::LinkerConfig::from_toml(&str));
+            match linker_config {
+                Ok(linker_config) => linker_config,
+                Err(err_str) => {
+                    eprintln!("ERR: {} | in file {}", err_str, linker_script_path);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => tasm::LinkerConfig::default(),
+    }
+}
+
+/// Accept the gcc spellings `-MD` and `-MF` of `--MD` and `--MF`.
+fn gcc_dependency_option(arg: String) -> String {
+    match arg.as_str() {
+        "-MD" | "-MF" => format!("-{}", arg),
+        _ => arg,
+    }
+}
+
+/// Makefile rule of `target`, without the source read from stdin.
+fn dependency_rule(target: &str, dependencies: &[PathBuf]) -> String {
+    let dependencies = dependencies
+        .iter()
+        .filter(|dependency| dependency.as_path() != Path::new(STDIN_NAME))
+        .cloned()
+        .collect::<Vec<_>>();
+    tasm::depfile::make_rule(target, &dependencies)
+}
+
+/// Write the `-MD` dependency file, named after the output unless `-MF` is given.
+fn write_dependency_file(args: &AssembleArgs, target: &str, dependencies: &[PathBuf]) {
+    let dependency_file_path = args.dependency_file.clone().unwrap_or_else(|| {
+        if target == STDIO_PATH {
+            format!("{}.d", DEFAULT_OUTPUT_NAME)
+        } else {
+            Path::new(target).with_extension("d").display().to_string()
+        }
+    });
+    if target != STDIO_PATH {
+        println!("INFO: Writing dependencies to: {}", dependency_file_path);
+    }
+    fs::write(dependency_file_path, dependency_rule(target, dependencies)).unwrap();
+}
+
+fn assembler(cache_dir: &Option<String>) -> tasm::Assembler {
+    match cache_dir {
+        Some(dir) => tasm::Assembler::with_cache_dir(dir),
+        None => tasm::Assembler::default(),
+    }
+}
+
+/// Sources of `tasm assemble`. The root file is read from stdin when `path` is `-`,
+/// its includes are then resolved from the current directory and the `-I` directories.
+fn source_provider(path: &str) -> (String, LayeredSources<'static>) {
+    let sources = LayeredSources::default();
+    if path != STDIO_PATH {
+        return (path.to_string(), sources.push(tasm::FileSystem));
+    }
+
+    let mut source_code = String::new();
+    if let Err(err) = std::io::stdin().read_to_string(&mut source_code) {
+        eprintln!("ERR: Cannot read stdin: {}", err);
+        std::process::exit(1);
+    }
+    let mut memory = MemorySources::default();
+    memory.insert(STDIN_NAME, source_code);
+    (
+        STDIN_NAME.to_string(),
+        sources.push(memory).push(tasm::FileSystem),
+    )
+}
+
+/// Print messages of the library, `INFO:` on stdout and `ERR:` on stderr. When stdout
+/// carries the output everything goes to stderr.
+fn print_diagnostics(diagnostics: &[Diagnostic], stdout_output: bool) {
+    for diagnostic in diagnostics {
+        match diagnostic.severity {
+            Severity::Info if !stdout_output => println!("INFO: {}", diagnostic),
+            Severity::Info => eprintln!("INFO: {}", diagnostic),
+            Severity::Error => eprintln!("ERR: {}", diagnostic),
+        }
+    }
+}
+
+/// Write an image as a raw binary (default `out.bin`) or a COE memory file (default `out.coe`).
+fn write_image(image: &tasm::Image, output: Option<String>, coe: bool) {
+    let format = if coe {
+        tasm::OutputFormat::Coe
+    } else {
+        tasm::OutputFormat::Bin
+    };
+    write_output(
+        output,
+        format.extension(),
+        format.extension(),
+        &image.encode(format, 0),
+    );
+}
+
+/// Write `bytes` to `output` (default `out.<extension>`), or to stdout when it is `-`.
+fn write_output(output: Option<String>, kind: &str, extension: &str, bytes: &[u8]) {
+    let output_file_path = output.unwrap_or(format!("{}.{}", DEFAULT_OUTPUT_NAME, extension));
+    if output_file_path == STDIO_PATH {
+        eprintln!("INFO: Writing {} output to stdout", kind);
+        let mut stdout = std::io::stdout().lock();
+        if let Err(err) = stdout.write_all(bytes).and_then(|_| stdout.flush()) {
+            eprintln!("ERR: Cannot write to stdout: {}", err);
+            std::process::exit(1);
+        }
+    } else {
+        println!("INFO: Writing {} output to: {}", kind, output_file_path);
+        fs::write(output_file_path, bytes).unwrap();
     }
 }
@@ -0,0 +1,251 @@
+use crate::parser::lexer::parse_number;
+use crate::parser::linker::LinkerConfig;
+use crate::parser::object::OutputFormat;
+
+pub const DEFAULT_MANIFEST_PATH: &str = "tasm.toml";
+
+/// Image of a target written in one format.
+pub struct Output {
+    pub format: OutputFormat,
+    pub path: String,
+}
+
+/// One image built by `tasm build`, paths are relative to the manifest.
+pub struct Target {
+    pub name: String,
+    pub entry: String,
+    pub include_dirs: Vec<String>,
+    pub defines: Vec<(String, u16)>,
+    /// Byte written in the gaps of the image
+    pub fill: u8,
+    /// Placement of the sections, from the `memory` and `section` arrays of the target
+    pub linker_config: LinkerConfig,
+    pub outputs: Vec<Output>,
+}
+
+/// Targets declared as `[[target]]` in `tasm.toml`.
+pub struct Manifest {
+    pub targets: Vec<Target>,
+}
+
+/// Parse a `-D` define: `NAME=VALUE`, or `NAME` alone for 1.
+pub fn parse_define(str: &str) -> Result<(String, u16), String> {
+    let (name, value) = match str.split_once('=') {
+        Some((name, value)) => (name, value),
+        None => (str, "1"),
+    };
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err(format!("Wrong define name: {}", name));
+    }
+    match parse_number::<u16>(value) {
+        Some(value) => Ok((name.to_string(), value)),
+        None => Err(format!("Wrong value of define {}: {}", name, value)),
+    }
+}
+
+fn get_string(table: &toml::Table, key: &str, name: &str) -> Result<Option<String>, String> {
+    match table.get(key) {
+        Some(value) => match value.as_str() {
+            Some(value) => Ok(Some(value.to_string())),
+            None => Err(format!("Target {} should have a string {}", name, key)),
+        },
+        None => Ok(None),
+    }
+}
+
+fn parse_target(table: &toml::Table) -> Result<Target, String> {
+    let name = get_string(table, "name", "")?.ok_or("Target should have a string name")?;
+    let entry = get_string(table, "entry", &name)?
+        .ok_or(format!("Target {} should have a string entry", name))?;
+
+    let mut include_dirs = vec![];
+    if let Some(dirs) = table.get("include_dirs") {
+        for dir in dirs.as_array().ok_or(format!(
+            "include_dirs of target {} should be an array",
+            name
+        ))? {
+            match dir.as_str() {
+                Some(dir) => include_dirs.push(dir.to_string()),
+                None => return Err(format!("include_dirs of target {} should be strings", name)),
+            }
+        }
+    }
+
+    let mut defines = vec![];
+    if let Some(table_defines) = table.get("defines") {
+        for (define, value) in table_defines
+            .as_table()
+            .ok_or(format!("defines of target {} should be a table", name))?
+        {
+            match value.as_integer() {
+                Some(value) if (0..=u16::MAX as i64).contains(&value) => {
+                    defines.push((define.to_string(), value as u16))
+                }
+                _ => {
+                    return Err(format!(
+                        "Wrong value of define {} in target {}",
+                        define, name
+                    ))
+                }
+            }
+        }
+    }
+
+    let fill = match table.get("fill").map(|fill| fill.as_integer()) {
+        None => 0,
+        Some(Some(fill)) if (0..=u8::MAX as i64).contains(&fill) => fill as u8,
+        Some(_) => return Err(format!("fill of target {} should be a byte", name)),
+    };
+
+    let mut outputs = vec![];
+    if let Some(table_outputs) = table.get("output") {
+        let table_outputs = table_outputs.as_array().ok_or(format!(
+            "output of target {} should be [[target.output]]",
+            name
+        ))?;
+        for output in table_outputs {
+            let output = output.as_table().ok_or(format!(
+                "output of target {} should be [[target.output]]",
+                name
+            ))?;
+            let format = get_string(output, "format", &name)?.unwrap_or("bin".to_string());
+            let format = OutputFormat::from_extension(&format).ok_or(format!(
+                "Unknow output format {} in target {}",
+                format, name
+            ))?;
+            let path = get_string(output, "path", &name)?.unwrap_or(format!(
+                "{}.{}",
+                name,
+                format.extension()
+            ));
+            outputs.push(Output { format, path });
+        }
+    }
+    if outputs.is_empty() {
+        outputs.push(Output {
+            format: OutputFormat::Bin,
+            path: format!("{}.bin", name),
+        });
+    }
+
+    let linker_config = LinkerConfig::from_table(table)
+        .map_err(|err_str| format!("{} in target {}", err_str, name))?;
+
+    Ok(Target {
+        name,
+        entry,
+        include_dirs,
+        defines,
+        fill,
+        linker_config,
+        outputs,
+    })
+}
+
+impl Manifest {
+    /// Read the `[[target]]` tables: `name`, `entry`, optional `include_dirs`, `defines`,
+    /// `fill`, `[[target.output]]` (`format`, `path`) and memory map as in a linker script.
+    pub fn from_toml(str: &str) -> Result<Self, String> {
+        let table = str.parse::<toml::Table>().map_err(|e| e.to_string())?;
+        let mut targets: Vec<Target> = vec![];
+        let empty = vec![];
+        let table_targets = match table.get("target") {
+            Some(targets) => targets.as_array().ok_or("target should be [[target]]")?,
+            None => &empty,
+        };
+        for target in table_targets {
+            let target = parse_target(target.as_table().ok_or("target should be [[target]]")?)?;
+            if targets.iter().any(|t| t.name == target.name) {
+                return Err(format!("Target {} is defined twice", target.name));
+            }
+            targets.push(target);
+        }
+        Ok(Self { targets })
+    }
+
+    /// Targets named in `names` in the order of the manifest, or every target when
+    /// `names` is empty.
+    pub fn select(self, names: &[String]) -> Result<Vec<Target>, String> {
+        if let Some(name) = names
+            .iter()
+            .find(|name| !self.targets.iter().any(|target| &target.name == *name))
+        {
+            return Err(format!("Unknow target: {}", name));
+        }
+        Ok(self
+            .targets
+            .into_iter()
+            .filter(|target| names.is_empty() || names.contains(&target.name))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = r#"
+[[target]]
+name = "rom"
+entry = "src/main.tasm"
+include_dirs = ["lib"]
+defines = { VERSION = 2, DEBUG = 0 }
+fill = 0xFF
+
+[[target.output]]
+format = "coe"
+path = "build/rom.coe"
+
+[[target.memory]]
+name = "rom"
+start = 0x8000
+size = 0x8000
+
+[[target.section]]
+name = "code"
+load = "rom"
+
+[[target]]
+name = "debug"
+entry = "src/main.tasm"
+defines = { DEBUG = 1 }
+"#;
+
+    #[test]
+    fn test_manifest() {
+        let manifest = Manifest::from_toml(MANIFEST).unwrap();
+        let rom = &manifest.targets[0];
+        assert_eq!(rom.include_dirs, vec!["lib".to_string()]);
+        assert!(rom.defines.contains(&("VERSION".to_string(), 2)));
+        assert_eq!(rom.fill, 0xFF);
+        assert_eq!(rom.outputs[0].format, OutputFormat::Coe);
+        assert_eq!(rom.linker_config.memories[0].start, 0x8000);
+
+        let debug = &manifest.targets[1];
+        assert_eq!(debug.outputs[0].path, "debug.bin");
+        assert!(debug.linker_config.sections.is_empty());
+
+        let names = ["debug".to_string()];
+        assert!(Manifest::from_toml(MANIFEST)
+            .unwrap()
+            .select(&["release".to_string()])
+            .is_err());
+        assert_eq!(manifest.select(&names).unwrap()[0].name, "debug");
+        assert!(Manifest::from_toml("[[target]]\nname = \"rom\"").is_err());
+        assert!(
+            Manifest::from_toml("[[target]]\nname = \"a\"\nentry = \"a.tasm\"\nfill = 256")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_define() {
+        assert_eq!(
+            parse_define("ORIGIN=$8000"),
+            Ok(("ORIGIN".to_string(), 0x8000))
+        );
+        assert_eq!(parse_define("DEBUG"), Ok(("DEBUG".to_string(), 1)));
+        assert!(parse_define("=1").is_err());
+        assert!(parse_define("SIZE=70000").is_err());
+    }
+}
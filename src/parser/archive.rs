@@ -0,0 +1,194 @@
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use super::linker::is_linker_symbol;
+use super::object::Object;
+use crate::diagnostic::Diagnostic;
+
+const ARCHIVE_FORMAT: &str = "tasm-archive-1";
+
+/// Object files bundled by `tasm ar`, with the object exporting each symbol.
+pub struct Archive {
+    objects: Vec<Object>,
+    index: HashMap<String, usize>,
+}
+
+impl Archive {
+    /// Bundle objects, a symbol exported by two of them is an error.
+    pub fn new(objects: Vec<Object>) -> Result<Self, String> {
+        let mut index = HashMap::new();
+        for (position, object) in objects.iter().enumerate() {
+            for (name, _) in object.symbols.iter() {
+                if let Some(other) = index.insert(name.to_string(), position) {
+                    return Err(format!(
+                        "Symbol {} is defined in {} and {}",
+                        name, objects[other].name, object.name
+                    ));
+                }
+            }
+        }
+        Ok(Self { objects, index })
+    }
+
+    pub fn to_json(&self) -> String {
+        json!({
+            "format": ARCHIVE_FORMAT,
+            "index": self.index,
+            "objects": self.objects.iter().map(Object::to_value).collect::<Vec<_>>(),
+        })
+        .to_string()
+    }
+
+    /// Read an archive, `None` if the file is not an archive.
+    pub fn from_json(str: &str) -> Result<Option<Self>, String> {
+        let value = serde_json::from_str::<Value>(str).map_err(|e| e.to_string())?;
+        if value.get("format").and_then(Value::as_str) != Some(ARCHIVE_FORMAT) {
+            return Ok(None);
+        }
+
+        let objects = value
+            .get("objects")
+            .and_then(Value::as_array)
+            .ok_or("Missing objects in archive")?
+            .iter()
+            .map(Object::from_value)
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut index = HashMap::new();
+        for (name, position) in value
+            .get("index")
+            .and_then(Value::as_object)
+            .ok_or("Missing index in archive")?
+        {
+            match position.as_u64() {
+                Some(position) if (position as usize) < objects.len() => {
+                    index.insert(name.to_string(), position as usize);
+                }
+                _ => return Err(format!("Wrong index of symbol {} in archive", name)),
+            }
+        }
+        Ok(Some(Self { objects, index }))
+    }
+}
+
+/// Move to `objects` the objects of the archives exporting a symbol used but not defined
+/// by `objects`, until every symbol that can be found is defined.
+pub fn pull_objects(
+    objects: &mut Vec<Object>,
+    archives: Vec<Archive>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let mut archives = archives
+        .into_iter()
+        .map(|archive| {
+            let members = archive.objects.into_iter().map(Some).collect::<Vec<_>>();
+            (members, archive.index)
+        })
+        .collect::<Vec<_>>();
+
+    loop {
+        let defined = objects
+            .iter()
+            .flat_map(|object| object.symbols.iter().map(|(name, _)| name.to_string()))
+            .collect::<HashSet<_>>();
+        let missing = objects
+            .iter()
+            .flat_map(Object::external_symbols)
+            .filter(|symbol| !defined.contains(*symbol) && !is_linker_symbol(symbol))
+            .map(str::to_string)
+            .collect::<BTreeSet<_>>();
+
+        let mut pulled = false;
+        for symbol in missing {
+            let member = archives.iter_mut().find_map(|(members, index)| {
+                index
+                    .get(&symbol)
+                    .and_then(|position| members[*position].take())
+            });
+            if let Some(object) = member {
+                diagnostics.push(Diagnostic::info(format!(
+                    "Pulling {} for symbol {}",
+                    object.name, symbol
+                )));
+                objects.push(object);
+                pulled = true;
+            }
+        }
+        if !pulled {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::LexCache;
+    use crate::parser::linker::LinkerConfig;
+    use crate::parser::source::FileSystem;
+    use crate::parser::IntermediateRepresentation;
+
+    fn object(path: &str) -> Object {
+        IntermediateRepresentation::new(path, &[], &[], &FileSystem, &LexCache::default(), &mut vec![])
+            .unwrap()
+            .to_object(path)
+    }
+
+    #[test]
+    fn test_archive_index() {
+        let archive = Archive::new(vec![
+            object("./test/objects/double.tasm"),
+            object("./test/objects/quad.tasm"),
+        ])
+        .unwrap();
+        let archive = Archive::from_json(&archive.to_json()).unwrap().unwrap();
+        assert_eq!(archive.index["double"], 0);
+        assert_eq!(archive.index["quad"], 1);
+
+        assert!(Archive::new(vec![
+            object("./test/objects/double.tasm"),
+            object("./test/objects/double.tasm"),
+        ])
+        .is_err());
+        assert!(
+            Archive::from_json(&object("./test/objects/double.tasm").to_json())
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_pull_objects() {
+        let archive = || {
+            Archive::new(vec![
+                object("./test/objects/quad.tasm"),
+                object("./test/objects/halve.tasm"),
+                object("./test/objects/double.tasm"),
+            ])
+            .unwrap()
+        };
+
+        // Only the object defining `double` is linked
+        let mut objects = vec![object("./test/objects/main.tasm")];
+        pull_objects(&mut objects, vec![archive()], &mut vec![]);
+        assert_eq!(objects.len(), 2);
+        assert_eq!(
+            Object::link(&objects, &LinkerConfig::default(), &mut vec![])
+                .unwrap()
+                .to_bytes(),
+            vec![0b10011111, 6, 0, 0b00001111, 0, 0, 0b00010110, 0b10100111]
+        );
+
+        // `quad` pulls `double` in turn
+        let mut objects = vec![object("./test/objects/quad_main.tasm")];
+        pull_objects(&mut objects, vec![archive()], &mut vec![]);
+        let names = objects.iter().map(|o| o.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "./test/objects/quad_main.tasm",
+                "./test/objects/quad.tasm",
+                "./test/objects/double.tasm"
+            ]
+        );
+    }
+}
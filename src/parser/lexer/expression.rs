@@ -0,0 +1,132 @@
+use super::generic::{parse_char, try_parse_number};
+use super::token::*;
+
+/// Value of a constant expression: numbers added or subtracted, each with an optional sign.
+pub fn evaluate(tokens: &[Token]) -> Result<i64, String> {
+    evaluate_with(tokens, &|c| Ok(c as i64))
+}
+
+/// Value of a constant expression, the character literals being valued by `char_value`.
+pub fn evaluate_with(
+    tokens: &[Token],
+    char_value: &dyn Fn(char) -> Result<i64, String>,
+) -> Result<i64, String> {
+    let not_valid = || format!("Expression is not valid: {}", tokens_to_string(tokens));
+    if tokens.is_empty() {
+        return Err("Expression is empty".to_string());
+    }
+
+    let mut value: i64 = 0;
+    let mut rest = tokens;
+    let mut operator = TokenKind::Plus;
+    loop {
+        let (negative, number, tail) = match rest {
+            [Token {
+                kind: sign @ (TokenKind::Plus | TokenKind::Minus),
+                ..
+            }, Token {
+                kind: TokenKind::Number(number),
+                ..
+            }, tail @ ..] => (*sign == TokenKind::Minus, number, tail),
+            [Token {
+                kind: TokenKind::Number(number),
+                ..
+            }, tail @ ..] => (false, number, tail),
+            _ => return Err(not_valid()),
+        };
+        let operand = match parse_char(number) {
+            Some(c) => char_value(c)?,
+            None => try_parse_number::<i64>(number)?,
+        };
+        let operand = if negative { -operand } else { operand };
+        value = match operator {
+            TokenKind::Minus => value.checked_sub(operand),
+            _ => value.checked_add(operand),
+        }
+        .ok_or_else(not_valid)?;
+
+        match tail {
+            [] => return Ok(value),
+            [Token {
+                kind: next @ (TokenKind::Plus | TokenKind::Minus),
+                ..
+            }, tail @ ..] => {
+                operator = next.clone();
+                rest = tail;
+            }
+            _ => return Err(not_valid()),
+        }
+    }
+}
+
+/// Evaluate a constant expression into an `u8` or `u16`. Negative values are written in
+/// two's complement, so a value is valid if it fits as signed or as unsigned.
+pub fn parse_constant<T: TryFrom<u64>>(tokens: &[Token]) -> Result<T, String> {
+    parse_constant_with(tokens, &|c| Ok(c as i64))
+}
+
+/// Evaluate a constant expression like `parse_constant`, valuing characters by `char_value`.
+pub fn parse_constant_with<T: TryFrom<u64>>(
+    tokens: &[Token],
+    char_value: &dyn Fn(char) -> Result<i64, String>,
+) -> Result<T, String> {
+    let value = evaluate_with(tokens, char_value)?;
+    let bits = std::mem::size_of::<T>() as u32 * 8;
+    let range = -(1i64 << (bits - 1))..(1i64 << bits);
+    let encoded = (value as u64) & ((1u64 << bits) - 1);
+    match T::try_from(encoded) {
+        Ok(encoded) if range.contains(&value) => Ok(encoded),
+        _ => Err(format!(
+            "Value {} of {} fits neither in a signed nor in an unsigned {} bits number",
+            value,
+            tokens_to_string(tokens),
+            bits
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn constant<T: TryFrom<u64>>(str: &str) -> Result<T, String> {
+        parse_constant::<T>(&tokenize(str).unwrap())
+    }
+
+    #[test]
+    fn test_expression() {
+        assert_eq!(evaluate(&tokenize("-1").unwrap()), Ok(-1));
+        assert_eq!(evaluate(&tokenize("$10 - 2 + -%11").unwrap()), Ok(11));
+        assert_eq!(evaluate(&tokenize("'a' - 'A'").unwrap()), Ok(32));
+        assert!(evaluate(&tokenize("").unwrap()).is_err());
+        assert!(evaluate(&tokenize("1 +").unwrap()).is_err());
+        assert!(evaluate(&tokenize("1 2").unwrap()).is_err());
+        assert!(evaluate(&tokenize("- - 1").unwrap()).is_err());
+        assert!(evaluate(&tokenize("label + 1").unwrap()).is_err());
+        let shifted = |c: char| Ok(c as i64 - 'A' as i64 + 1);
+        assert_eq!(
+            evaluate_with(&tokenize("'B' + 1").unwrap(), &shifted),
+            Ok(3)
+        );
+
+        // Negative values are encoded in two's complement
+        assert_eq!(constant::<u8>("-1"), Ok(0xFF));
+        assert_eq!(constant::<u8>("-128"), Ok(0x80));
+        assert_eq!(constant::<u8>("255"), Ok(0xFF));
+        assert_eq!(constant::<u8>("10 - 20"), Ok(0xF6));
+        assert!(constant::<u8>("-129").is_err());
+        assert!(constant::<u8>("$FF + 1").is_err());
+
+        // Addresses wrap the same way on 16 bits
+        assert_eq!(constant::<u16>("0 - 1"), Ok(0xFFFF));
+        assert_eq!(constant::<u16>("$8000 + $7FFF"), Ok(0xFFFF));
+        assert_eq!(constant::<u16>("-32768"), Ok(0x8000));
+        assert_eq!(
+            constant::<u16>("$FFFF + 1"),
+            Err(
+                "Value 65536 of $FFFF+1 fits neither in a signed nor in an unsigned 16 bits number"
+                    .to_string()
+            )
+        );
+    }
+}
@@ -1,134 +1,606 @@
-use super::generic::parse_number;
-use regex::Regex;
+use super::expression::parse_constant;
+use super::generic::{parse_char, try_parse_number, unescape};
+use super::label::parse_label_reference;
+use super::token::*;
 
-#[derive(Debug, PartialEq)]
+pub const FLAG_KEYWORDS: [&str; 17] = [
+    "org",
+    "section",
+    "include",
+    "include_once",
+    "incbin",
+    "align",
+    "res",
+    "ds",
+    "assert_page",
+    "label",
+    "set",
+    "global",
+    "extern",
+    "scope",
+    "endscope",
+    "ascii",
+    "charmap",
+];
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Include {
+    pub path: String,
+    /// Namespace of the labels of the file, `.include "<PATH>" as <NAMESPACE>`
+    pub namespace: Option<String>,
+    /// Skip the file if it was already included, `.include_once`
+    pub once: bool,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct IncludeBinary {
+    pub path: String,
+    /// Number of bytes skipped at the start of the file
+    pub offset: usize,
+    /// Number of bytes included, up to the end of the file if not set
+    pub length: Option<usize>,
+}
+
+/// Characters from `first` to `last` encoded from `code` on, `.charmap`
+#[derive(Debug, PartialEq, Clone)]
+pub struct Charmap {
+    pub first: char,
+    pub last: char,
+    pub code: u8,
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum Flag {
     Org(u16),
-    Include(String),
+    /// Named section placed by the linker
+    Section(String),
+    Include(Include),
+    IncludeBinary(IncludeBinary),
+    /// Alignment and optional fill byte of the padding
+    Align((u16, Option<u8>)),
+    /// Number of bytes reserved without emitting them
+    Reserve(u16),
+    /// Label at the start of a block that should stay within one page
+    AssertPage(String),
     Label((String, u16)),
+    /// Like `Label` but can be defined again by another `.set`
+    Set((String, u16)),
+    /// Label exported to the other object files
+    Global(String),
+    /// Label defined in another object file
+    Extern(String),
+    Scope(String),
+    EndScope,
+    /// Characters of a string, with its escape sequences replaced, encoded with the character map
+    Ascii(String),
+    Charmap(Charmap),
 }
 
 impl Flag {
-    pub fn new(str: &str) -> Result<Option<Self>, String> {
-        if str.starts_with('.') {
-            let line_splited = str.split(" ").collect::<Vec<&str>>();
-            let keyword = &line_splited[0][1..];
-            match keyword {
-                "org" => {
-                    if line_splited.len() == 2 {
-                        if let Some(addr) = parse_number::<u16>(line_splited[1]) {
-                            Ok(Some(Flag::Org(addr)))
-                        } else {
-                            Err(format!("Cannot parse address: {}", line_splited[1]))
+    pub fn new(tokens: &[Token]) -> Result<Option<Self>, String> {
+        if tokens.first().map(|t| &t.kind) != Some(&TokenKind::Dot) {
+            return Ok(None);
+        }
+
+        let keyword = match tokens.get(1) {
+            Some(token) if token.span.start == tokens[0].span.end => token.identifier(),
+            _ => None,
+        };
+        let Some(keyword) = keyword else {
+            return Err(format!("Unknow flag keyword: {}", tokens_to_string(tokens)));
+        };
+        let arguments = &tokens[2..];
+
+        match keyword.to_lowercase().as_str() {
+            "org" => {
+                if arguments.is_empty() {
+                    Err("Argument does not match should be: .org <ADDR>".to_string())
+                } else {
+                    Ok(Some(Flag::Org(parse_constant::<u16>(arguments)?)))
+                }
+            }
+            "include" | "include_once" => {
+                let namespace = match arguments {
+                    [_] => None,
+                    [_, keyword, namespace] if keyword.identifier() == Some("as") => {
+                        match namespace.identifier() {
+                            Some(namespace) => Some(namespace.to_string()),
+                            None => {
+                                return Err(format!("Namespace is not correct: {}", namespace.kind))
+                            }
                         }
-                    } else {
-                        Err("Argument does not match should be: .org <ADDR>".to_string())
                     }
+                    _ => {
+                        return Err(format!(
+                            "Argument does not match should be: .{} \"<PATH>\" [as <NAMESPACE>]",
+                            keyword
+                        ))
+                    }
+                };
+                match &arguments[0].kind {
+                    TokenKind::String(path_str) => Ok(Some(Flag::Include(Include {
+                        path: path_str.to_string(),
+                        namespace,
+                        once: keyword.eq_ignore_ascii_case("include_once"),
+                    }))),
+                    _ => Err("File path need to be string format!".to_string()),
                 }
-                "include" => {
-                    if line_splited.len() == 2 {
-                        if line_splited[1].starts_with('"') && line_splited[1].ends_with('"') {
-                            let path_str = &line_splited[1][1..line_splited[1].len() - 1];
-                            Ok(Some(Flag::Include(path_str.to_string())))
-                        } else {
-                            Err("File path need to be string format!".to_string())
-                        }
-                    } else {
-                        Err("Argument does not match should be: .include \"<PATH>\"".to_string())
+            }
+            "incbin" => {
+                let mut arguments = arguments.split(|t| t.kind == TokenKind::Comma);
+                let path = match arguments.next() {
+                    Some(
+                        [Token {
+                            kind: TokenKind::String(path_str),
+                            ..
+                        }],
+                    ) => path_str.to_string(),
+                    Some([_]) => return Err("File path need to be string format!".to_string()),
+                    _ => {
+                        return Err(
+                            "Argument does not match should be: .incbin \"<PATH>\"[,<OFFSET>[,<LENGTH>]]"
+                                .to_string(),
+                        )
                     }
+                };
+                let numbers = arguments
+                    .map(parse_number_argument::<usize>)
+                    .collect::<Result<Vec<_>, _>>()?;
+                match numbers[..] {
+                    [] | [_] | [_, _] => Ok(Some(Flag::IncludeBinary(IncludeBinary {
+                        path,
+                        offset: numbers.first().copied().unwrap_or(0),
+                        length: numbers.get(1).copied(),
+                    }))),
+                    _ => Err(
+                        "Argument does not match should be: .incbin \"<PATH>\"[,<OFFSET>[,<LENGTH>]]"
+                            .to_string(),
+                    ),
                 }
-                "label" => {
-                    if line_splited.len() == 3 {
-                        let re = Regex::new(r"^[a-z_0-9]+$").unwrap();
-                        if re.is_match(line_splited[1]) {
-                            if let Some(addr) = parse_number::<u16>(line_splited[2]) {
-                                Ok(Some(Flag::Label((line_splited[1].to_string(), addr))))
-                            } else {
-                                Err(format!("Cannot parse address: {}", line_splited[2]))
-                            }
-                        } else {
-                            Err(format!("Label is not correct (only [a-z_0-9]): {}", line_splited[1]))
-                        }
-                    } else {
-                        Err("Argument does not match should be: .label <NAME> <ADDR>".to_string())
+            }
+            "align" => {
+                let arguments = arguments
+                    .split(|t| t.kind == TokenKind::Comma)
+                    .collect::<Vec<_>>();
+                let (alignment, fill) = match arguments[..] {
+                    [alignment] if !alignment.is_empty() => (alignment, None),
+                    [alignment, fill] => (alignment, Some(parse_number_argument::<u8>(fill)?)),
+                    _ => {
+                        return Err(
+                            "Argument does not match should be: .align <N>[,<FILL>]".to_string()
+                        )
+                    }
+                };
+                match parse_number_argument::<u16>(alignment)? {
+                    0 => Err("Alignment cannot be 0".to_string()),
+                    alignment => Ok(Some(Flag::Align((alignment, fill)))),
+                }
+            }
+            "res" | "ds" => match arguments {
+                [_] => Ok(Some(Flag::Reserve(parse_number_argument::<u16>(
+                    arguments,
+                )?))),
+                _ => Err(format!(
+                    "Argument does not match should be: .{} <SIZE>",
+                    keyword.to_lowercase()
+                )),
+            },
+            "assert_page" => {
+                let label = match arguments {
+                    [] => {
+                        return Err(
+                            "Argument does not match should be: .assert_page <LABEL>".to_string()
+                        )
                     }
+                    [name] => name.identifier().map(str::to_string),
+                    _ => parse_label_reference(arguments),
+                };
+                match label {
+                    Some(label) => Ok(Some(Flag::AssertPage(label))),
+                    None => Err(format!(
+                        "Label is not correct: {}",
+                        tokens_to_string(arguments)
+                    )),
                 }
-                _ => Err(format!("Unknow flag keyword: .{}", keyword)),
             }
-        } else {
-            Ok(None)
+            "scope" => match arguments {
+                [name] => match name.identifier() {
+                    Some(name) => Ok(Some(Flag::Scope(name.to_string()))),
+                    None => Err(format!("Scope name is not correct: {}", name.kind)),
+                },
+                _ => Err("Argument does not match should be: .scope <NAME>".to_string()),
+            },
+            "section" => match arguments {
+                [name] => match name.identifier() {
+                    Some(name) => Ok(Some(Flag::Section(name.to_string()))),
+                    None => Err(format!("Section name is not correct: {}", name.kind)),
+                },
+                _ => Err("Argument does not match should be: .section <NAME>".to_string()),
+            },
+            "global" | "extern" => {
+                let label = match arguments {
+                    [name] => name.identifier().map(str::to_string),
+                    _ => parse_label_reference(arguments),
+                };
+                match label {
+                    Some(label) if keyword.eq_ignore_ascii_case("global") => {
+                        Ok(Some(Flag::Global(label)))
+                    }
+                    Some(label) => Ok(Some(Flag::Extern(label))),
+                    None => Err(format!(
+                        "Argument does not match should be: .{} <LABEL>",
+                        keyword.to_lowercase()
+                    )),
+                }
+            }
+            "endscope" => match arguments {
+                [] => Ok(Some(Flag::EndScope)),
+                _ => Err("Argument does not match should be: .endscope".to_string()),
+            },
+            "ascii" => match arguments {
+                [Token {
+                    kind: TokenKind::String(str),
+                    ..
+                }] => Ok(Some(Flag::Ascii(unescape(str)?))),
+                _ => Err("Argument does not match should be: .ascii \"<STRING>\"".to_string()),
+            },
+            "charmap" => {
+                let arguments = arguments
+                    .split(|t| t.kind == TokenKind::Comma)
+                    .collect::<Vec<_>>();
+                let (first, last, code) = match arguments[..] {
+                    [first, code] => (parse_char_argument(first)?, None, code),
+                    [first, last, code] => (
+                        parse_char_argument(first)?,
+                        Some(parse_char_argument(last)?),
+                        code,
+                    ),
+                    _ => return Err(
+                        "Argument does not match should be: .charmap '<CHAR>'[,'<LAST>'],<CODE>"
+                            .to_string(),
+                    ),
+                };
+                let last = last.unwrap_or(first);
+                let code = parse_constant::<u8>(code)?;
+                if last < first {
+                    return Err(format!(
+                        "Character range is empty: {:?} to {:?}",
+                        first, last
+                    ));
+                }
+                if code as u32 + (last as u32 - first as u32) > u8::MAX as u32 {
+                    return Err(format!(
+                        "Codes of the characters {:?} to {:?} go past $FF",
+                        first, last
+                    ));
+                }
+                Ok(Some(Flag::Charmap(Charmap { first, last, code })))
+            }
+            "label" => parse_name_and_address("label", arguments).map(|v| Some(Flag::Label(v))),
+            "set" => parse_name_and_address("set", arguments).map(|v| Some(Flag::Set(v))),
+            _ => Err(format!("Unknow flag keyword: .{}", keyword)),
         }
     }
 }
 
+/// Parse an argument made of a single number.
+fn parse_number_argument<T: TryFrom<u64>>(argument: &[Token]) -> Result<T, String> {
+    match argument {
+        [Token {
+            kind: TokenKind::Number(number),
+            ..
+        }] => try_parse_number::<T>(number),
+        _ => Err(format!(
+            "Cannot parse number: {}",
+            tokens_to_string(argument)
+        )),
+    }
+}
+
+/// Parse an argument made of a single character literal.
+fn parse_char_argument(argument: &[Token]) -> Result<char, String> {
+    match argument {
+        [Token {
+            kind: TokenKind::Number(literal),
+            ..
+        }] => parse_char(literal).ok_or(format!("Cannot parse character: {}", literal)),
+        _ => Err(format!(
+            "Cannot parse character: {}",
+            tokens_to_string(argument)
+        )),
+    }
+}
+
+/// Parse the `<NAME> <ADDR>` arguments of `.label` and `.set`, the address can be an expression.
+fn parse_name_and_address(keyword: &str, arguments: &[Token]) -> Result<(String, u16), String> {
+    match arguments {
+        [name, addr @ ..] if !addr.is_empty() => match name.identifier() {
+            Some(name) => Ok((name.to_string(), parse_constant::<u16>(addr)?)),
+            None => Err(format!("Label is not correct: {}", name.kind)),
+        },
+        _ => Err(format!(
+            "Argument does not match should be: .{} <NAME> <ADDR>",
+            keyword
+        )),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn parse(str: &str) -> Result<Option<Flag>, String> {
+        Flag::new(&tokenize(str)?)
+    }
+
     #[test]
     fn test_empty_flag() {
-        let new_instance = Flag::new(". $8000");
+        let new_instance = parse(". $8000");
         assert!(new_instance.is_err());
 
-        let new_instance = Flag::new(".");
+        let new_instance = parse(".");
         assert!(new_instance.is_err());
     }
 
     #[test]
     fn test_org_flag() {
-        let new_instance = Flag::new(".org $8000");
+        let new_instance = parse(".org $8000");
         assert!(new_instance.is_ok());
         assert_eq!(new_instance.unwrap().unwrap(), Flag::Org(32768));
 
-        let new_instance = Flag::new(".org #5");
+        let new_instance = parse(".ORG\t$8000");
+        assert!(new_instance.is_ok());
+        assert_eq!(new_instance.unwrap().unwrap(), Flag::Org(32768));
+
+        let new_instance = parse(". org $8000");
         assert!(new_instance.is_err());
 
-        let new_instance = Flag::new(".org");
+        let new_instance = parse(".org #5");
+        assert!(new_instance.is_err());
+
+        let new_instance = parse(".org");
         assert!(new_instance.is_err());
     }
 
     #[test]
     fn test_include_flag() {
-        let new_instance = Flag::new(".include \"./test/test.tasm\"");
+        let new_instance = parse(".include \"./test/test.tasm\"");
+        assert!(new_instance.is_ok());
+        assert_eq!(
+            new_instance.unwrap().unwrap(),
+            Flag::Include(Include {
+                path: "./test/test.tasm".to_string(),
+                namespace: None,
+                once: false
+            })
+        );
+
+        let new_instance = parse(".include \"math.tasm\" as math");
         assert!(new_instance.is_ok());
         assert_eq!(
             new_instance.unwrap().unwrap(),
-            Flag::Include("./test/test.tasm".to_string())
+            Flag::Include(Include {
+                path: "math.tasm".to_string(),
+                namespace: Some("math".to_string()),
+                once: false
+            })
         );
 
-        let new_instance = Flag::new(".include ./test/test.tasm");
+        let new_instance = parse(".include_once \"math.tasm\"");
+        assert!(new_instance.is_ok());
+        assert!(matches!(
+            new_instance.unwrap().unwrap(),
+            Flag::Include(Include { once: true, .. })
+        ));
+
+        let new_instance = parse(".include \"math.tasm\" as");
+        assert!(new_instance.is_err());
+
+        let new_instance = parse(".include \"math.tasm\" as $10");
+        assert!(new_instance.is_err());
+
+        let new_instance = parse(".include ./test/test.tasm");
         assert!(new_instance.is_err());
 
-        let new_instance = Flag::new(".include");
+        let new_instance = parse(".include");
         assert!(new_instance.is_err());
     }
 
+    #[test]
+    fn test_incbin_flag() {
+        let new_instance = parse(".incbin \"font.bin\"");
+        assert!(new_instance.is_ok());
+        assert_eq!(
+            new_instance.unwrap().unwrap(),
+            Flag::IncludeBinary(IncludeBinary {
+                path: "font.bin".to_string(),
+                offset: 0,
+                length: None
+            })
+        );
+
+        let new_instance = parse(".incbin \"font.bin\", $10, 8");
+        assert!(new_instance.is_ok());
+        assert_eq!(
+            new_instance.unwrap().unwrap(),
+            Flag::IncludeBinary(IncludeBinary {
+                path: "font.bin".to_string(),
+                offset: 16,
+                length: Some(8)
+            })
+        );
+
+        assert!(parse(".incbin").is_err());
+        assert!(parse(".incbin font").is_err());
+        assert!(parse(".incbin \"font.bin\",").is_err());
+        assert!(parse(".incbin \"font.bin\",1,2,3").is_err());
+        assert!(parse(".incbin \"font.bin\",label").is_err());
+    }
+
+    #[test]
+    fn test_space_flags() {
+        assert_eq!(
+            parse(".align $100").unwrap().unwrap(),
+            Flag::Align((256, None))
+        );
+        assert_eq!(
+            parse(".align 4,$ff").unwrap().unwrap(),
+            Flag::Align((4, Some(255)))
+        );
+        assert!(parse(".align").is_err());
+        assert!(parse(".align 0").is_err());
+        assert!(parse(".align 4,$100").is_err());
+
+        assert_eq!(parse(".res 16").unwrap().unwrap(), Flag::Reserve(16));
+        assert_eq!(parse(".DS $10").unwrap().unwrap(), Flag::Reserve(16));
+        assert!(parse(".res").is_err());
+        assert!(parse(".res 1,2").is_err());
+
+        assert_eq!(
+            parse(".assert_page table").unwrap().unwrap(),
+            Flag::AssertPage("table".to_string())
+        );
+        assert_eq!(
+            parse(".assert_page math::table").unwrap().unwrap(),
+            Flag::AssertPage("math::table".to_string())
+        );
+        assert!(parse(".assert_page").is_err());
+        assert!(parse(".assert_page $10").is_err());
+    }
+
+    #[test]
+    fn test_set_flag() {
+        let new_instance = parse(".set counter $10");
+        assert!(new_instance.is_ok());
+        assert_eq!(
+            new_instance.unwrap().unwrap(),
+            Flag::Set(("counter".to_string(), 16))
+        );
+
+        assert!(parse(".set counter").is_err());
+        assert!(parse(".set $10 counter").is_err());
+    }
+
+    #[test]
+    fn test_scope_flag() {
+        let new_instance = parse(".scope math");
+        assert!(new_instance.is_ok());
+        assert_eq!(
+            new_instance.unwrap().unwrap(),
+            Flag::Scope("math".to_string())
+        );
+
+        let new_instance = parse(".endscope");
+        assert!(new_instance.is_ok());
+        assert_eq!(new_instance.unwrap().unwrap(), Flag::EndScope);
+
+        assert!(parse(".scope").is_err());
+        assert!(parse(".scope $10").is_err());
+        assert!(parse(".endscope math").is_err());
+    }
+
+    #[test]
+    fn test_section_flag() {
+        let new_instance = parse(".section data");
+        assert!(new_instance.is_ok());
+        assert_eq!(
+            new_instance.unwrap().unwrap(),
+            Flag::Section("data".to_string())
+        );
+
+        assert!(parse(".section").is_err());
+        assert!(parse(".section code data").is_err());
+    }
+
+    #[test]
+    fn test_global_extern_flag() {
+        let new_instance = parse(".global init");
+        assert!(new_instance.is_ok());
+        assert_eq!(
+            new_instance.unwrap().unwrap(),
+            Flag::Global("init".to_string())
+        );
+
+        let new_instance = parse(".extern math::double");
+        assert!(new_instance.is_ok());
+        assert_eq!(
+            new_instance.unwrap().unwrap(),
+            Flag::Extern("math::double".to_string())
+        );
+
+        assert!(parse(".global").is_err());
+        assert!(parse(".extern $10").is_err());
+    }
+
+    #[test]
+    fn test_ascii_flag() {
+        assert_eq!(
+            parse(".ascii \"Hi \\\"you\\\"\\n\\x00\""),
+            Ok(Some(Flag::Ascii("Hi \"you\"\n\0".to_string())))
+        );
+        assert!(parse(".ascii").is_err());
+        assert!(parse(".ascii hello").is_err());
+        assert!(parse(".ascii \"\\q\"").is_err());
+    }
+
+    #[test]
+    fn test_charmap_flag() {
+        assert_eq!(
+            parse(".charmap 'A', $10"),
+            Ok(Some(Flag::Charmap(Charmap {
+                first: 'A',
+                last: 'A',
+                code: 0x10
+            })))
+        );
+        assert_eq!(
+            parse(".charmap 'a','z',$41"),
+            Ok(Some(Flag::Charmap(Charmap {
+                first: 'a',
+                last: 'z',
+                code: 0x41
+            })))
+        );
+        assert_eq!(
+            parse(".charmap '°', $DF"),
+            Ok(Some(Flag::Charmap(Charmap {
+                first: '°',
+                last: '°',
+                code: 0xDF
+            })))
+        );
+        assert!(parse(".charmap 'A'").is_err());
+        assert!(parse(".charmap $41, $10").is_err());
+        assert!(parse(".charmap 'z', 'a', 0").is_err());
+        assert!(parse(".charmap 'a', 'z', $F0").is_err());
+    }
+
     #[test]
     fn test_label_flag() {
-        let new_instance = Flag::new(".label test 0");
+        let new_instance = parse(".label test 0");
         assert!(new_instance.is_ok());
         assert_eq!(
             new_instance.unwrap().unwrap(),
             Flag::Label(("test".to_string(), 0))
         );
 
-        let new_instance = Flag::new(".label test_0 $8100");
+        let new_instance = parse(".label test_0 $8100");
         assert!(new_instance.is_ok());
         assert_eq!(
             new_instance.unwrap().unwrap(),
             Flag::Label(("test_0".to_string(), 33024))
         );
 
-        let new_instance = Flag::new(".label");
+        let new_instance = parse(".label");
         assert!(new_instance.is_err());
 
-        let new_instance = Flag::new(".label test");
+        let new_instance = parse(".label test");
         assert!(new_instance.is_err());
 
-        let new_instance = Flag::new(".label test aze");
+        let new_instance = parse(".label test aze");
         assert!(new_instance.is_err());
 
-        let new_instance = Flag::new(".label wr-ong 0");
+        let new_instance = parse(".label wr-ong 0");
         assert!(new_instance.is_err());
     }
 }
@@ -1,15 +1,84 @@
-pub fn parse_number<T: num::Integer + std::str::FromStr>(str: &str) -> Option<T> {
-    if let Some(hex_number) = str.strip_prefix('$') {
-        if let Ok(num) = T::from_str_radix(hex_number, 16) {
-            Some(num)
-        } else {
-            None
+/// Replace the escape sequences of a character or string literal: `\n`, `\r`, `\t`, `\0`,
+/// `\\`, `\'`, `\"` and `\xHH`.
+pub fn unescape(str: &str) -> Result<String, String> {
+    let mut unescaped = String::new();
+    let mut chars = str.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
         }
-    } else if let Ok(num) = str.parse::<T>() {
-        Some(num)
+        let escaped = match chars.next() {
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('0') => '\0',
+            Some(c @ ('\\' | '\'' | '"')) => c,
+            Some('x') => {
+                let digits = chars.by_ref().take(2).collect::<String>();
+                if digits.len() != 2 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(format!("Escape sequence is not valid: \\x{}", digits));
+                }
+                u8::from_str_radix(&digits, 16).unwrap() as char
+            }
+            Some(c) => return Err(format!("Escape sequence is not valid: \\{}", c)),
+            None => return Err("Escape sequence is not terminated".to_string()),
+        };
+        unescaped.push(escaped);
+    }
+    Ok(unescaped)
+}
+
+/// Character of a character literal like `'A'` or `'\\n'`.
+pub fn parse_char(str: &str) -> Option<char> {
+    let unescaped = unescape(str.strip_prefix('\'')?.strip_suffix('\'')?).ok()?;
+    let mut chars = unescaped.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(c),
+        _ => None,
+    }
+}
+
+/// Value of a number literal: decimal, hexadecimal (`$FF`, `0xFF`), binary (`%1010`, `0b1010`),
+/// octal (`0o17`) or character (`'A'`). Digits can be separated by `_`.
+fn parse_literal(str: &str) -> Option<u64> {
+    if str.starts_with('\'') {
+        return parse_char(str).map(|c| c as u64);
+    }
+
+    let prefix = str.get(..2).map(|prefix| prefix.to_ascii_lowercase());
+    let (digits, radix) = if let Some(digits) = str.strip_prefix('$') {
+        (digits, 16)
+    } else if let Some(digits) = str.strip_prefix('%') {
+        (digits, 2)
     } else {
-        None
+        match prefix.as_deref() {
+            Some("0x") => (&str[2..], 16),
+            Some("0b") => (&str[2..], 2),
+            Some("0o") => (&str[2..], 8),
+            _ => (str, 10),
+        }
+    };
+    if digits.starts_with('_') || !digits.chars().all(|c| c == '_' || c.is_digit(radix)) {
+        return None;
     }
+    u64::from_str_radix(&digits.replace('_', ""), radix).ok()
+}
+
+/// Like `parse_number`, with the reason the number is rejected.
+pub fn try_parse_number<T: TryFrom<u64>>(str: &str) -> Result<T, String> {
+    let value = parse_literal(str).ok_or(format!("Number is not valid: {}", str))?;
+    T::try_from(value).map_err(|_| {
+        format!(
+            "Number {} does not fit in {} bits",
+            str,
+            std::mem::size_of::<T>() * 8
+        )
+    })
+}
+
+pub fn parse_number<T: TryFrom<u64>>(str: &str) -> Option<T> {
+    try_parse_number(str).ok()
 }
 
 pub fn trim_line(str: &str) -> &str {
@@ -47,5 +116,47 @@ mod tests {
         // Hex as decimal
         let new_number = parse_number::<u8>("ab");
         assert!(new_number.is_none());
+
+        // Other bases and separators
+        assert_eq!(parse_number::<u8>("%1010_0101"), Some(0b1010_0101));
+        assert_eq!(parse_number::<u8>("0B1111"), Some(15));
+        assert_eq!(parse_number::<u16>("0x80_00"), Some(0x8000));
+        assert_eq!(parse_number::<u16>("0o17"), Some(15));
+        assert_eq!(parse_number::<u16>("1_000"), Some(1000));
+        assert_eq!(parse_number::<u8>("0b102"), None);
+        assert_eq!(parse_number::<u8>("$_FF"), None);
+        assert_eq!(parse_number::<u8>("0x"), None);
+
+        // Character literals
+        assert_eq!(parse_number::<u8>("'A'"), Some(65));
+        assert_eq!(parse_number::<u8>("'\\n'"), Some(10));
+        assert_eq!(parse_number::<u8>("'\\x7F'"), Some(0x7F));
+        assert_eq!(parse_number::<u8>("'\\''"), Some(39));
+        assert_eq!(parse_number::<u8>("'AB'"), None);
+        assert_eq!(parse_number::<u8>("''"), None);
+
+        // Overflow depends on the size of the value
+        assert_eq!(
+            try_parse_number::<u8>("%1_0000_0000"),
+            Err("Number %1_0000_0000 does not fit in 8 bits".to_string())
+        );
+        assert_eq!(try_parse_number::<u16>("%1_0000_0000"), Ok(256));
+        assert_eq!(
+            try_parse_number::<u16>("$10000"),
+            Err("Number $10000 does not fit in 16 bits".to_string())
+        );
+        assert_eq!(
+            try_parse_number::<u16>("12a"),
+            Err("Number is not valid: 12a".to_string())
+        );
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape("a\\tb\\0\\\\"), Ok("a\tb\0\\".to_string()));
+        assert_eq!(unescape("\\x41\\\""), Ok("A\"".to_string()));
+        assert!(unescape("\\q").is_err());
+        assert!(unescape("\\x4").is_err());
+        assert!(unescape("\\").is_err());
     }
 }
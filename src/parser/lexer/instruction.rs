@@ -1,4 +1,15 @@
-use super::generic::parse_number;
+use super::expression::{evaluate, evaluate_with, parse_constant};
+use super::generic::parse_char;
+use super::label::parse_label_reference;
+use super::token::*;
+
+pub const MNEMONICS: [&str; 25] = [
+    "halt", "load", "tf", "store", "push", "pull", "incr", "add", "sub", "and", "or", "eor",
+    "shift_right", "shift_left", "jump", "bcc", "bcs", "bzc", "bzs", "bnc", "bns", "boc", "bos",
+    "jsr", "rts",
+];
+
+pub const REGISTERS: [&str; 4] = ["rx", "ry", "ra", "rb"];
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 #[repr(u8)]
@@ -68,12 +79,16 @@ pub enum InstructionData {
     DoubleRegisters(Registers, Registers),
 }
 
+#[derive(Clone)]
 pub enum InstructionLinkedData {
     Immediate(u8),
+    /// Immediate expression with character literals, evaluated with the character map when parsed
+    Characters(Vec<Token>),
     Relative(u16),
     NotResolvedRelative(String),
 }
 
+#[derive(Clone)]
 pub struct Instruction {
     pub opcode: Opcode,
     pub addressing_mode: AddressingMode,
@@ -81,16 +96,56 @@ pub struct Instruction {
     pub size: u16,
     pub linked_data: Option<InstructionLinkedData>,
 }
-impl Instruction {
-    pub fn new(str: &str) -> Result<Option<Self>, String> {
-        let (keyword, data) = {
-            let line_splited = str.split(" ").collect::<Vec<&str>>();
-            let data = line_splited.get(1).map(|s| s.trim_ascii());
+fn parse_register(operand: &[Token]) -> Result<Registers, String> {
+    let register = match operand {
+        [token] => match token.identifier().map(|s| s.to_lowercase()).as_deref() {
+            Some("rx") => Some(Registers::Rx),
+            Some("ry") => Some(Registers::Ry),
+            Some("ra") => Some(Registers::Ra),
+            Some("rb") => Some(Registers::Rb),
+            _ => None,
+        },
+        _ => None,
+    };
+    register.ok_or(format!("Unknow register: {}", tokens_to_string(operand)))
+}
 
-            (line_splited[0], data)
+fn parse_address(operand: &[Token]) -> Result<InstructionLinkedData, String> {
+    match operand {
+        [Token {
+            kind: TokenKind::Number(_) | TokenKind::Minus,
+            ..
+        }, ..] => Ok(InstructionLinkedData::Relative(parse_constant::<u16>(
+            operand,
+        )?)),
+        [Token {
+            kind: TokenKind::Identifier(label),
+            ..
+        }] => Ok(InstructionLinkedData::NotResolvedRelative(label.to_string())),
+        _ => match parse_label_reference(operand) {
+            Some(label) => Ok(InstructionLinkedData::NotResolvedRelative(label)),
+            None => Err(format!("Address is not valid: {}", tokens_to_string(operand))),
+        },
+    }
+}
+
+impl Instruction {
+    pub fn new(tokens: &[Token]) -> Result<Option<Self>, String> {
+        let Some(keyword) = tokens.first().and_then(|t| t.identifier()) else {
+            return Ok(None);
+        };
+        let keyword = keyword.to_lowercase();
+        let data: Option<Vec<&[Token]>> = if tokens.len() > 1 {
+            Some(
+                tokens[1..]
+                    .split(|t| t.kind == TokenKind::Comma)
+                    .collect(),
+            )
+        } else {
+            None
         };
 
-        match keyword {
+        match keyword.as_str() {
             "halt" => {
                 if data.is_some() {
                     return Err("Data part of instruction should be none".to_string());
@@ -109,47 +164,38 @@ impl Instruction {
                     return Err("Data part of instruction is none".to_string());
                 }
 
-                if let Some((register_str, data_str)) = data.unwrap().split_once(",") {
-                    let register: Option<Registers> = match register_str {
-                        "rx" => Some(Registers::Rx),
-                        "ry" => Some(Registers::Ry),
-                        "ra" => Some(Registers::Ra),
-                        "rb" => Some(Registers::Rb),
-                        _ => None,
-                    };
-                    if register.is_none() {
-                        return Err(format!("Unknow register: {}", register_str));
-                    }
-                    if data_str.is_empty() {
+                if let [register_tokens, data_tokens] = data.unwrap().as_slice() {
+                    let register = parse_register(register_tokens)?;
+                    if data_tokens.is_empty() {
                         return Err("Value to load is none".to_string());
                     }
 
                     let addressing_mode;
                     let linked_data;
-                    let data_trimmed = data_str.trim_ascii().to_string();
-                    if data_trimmed.starts_with("#") {
-                        if let Some(value) =
-                            parse_number::<u8>(data_trimmed.replace("#", "").as_str())
-                        {
-                            addressing_mode = AddressingMode::Immediate;
-                            linked_data = InstructionLinkedData::Immediate(value);
+                    if data_tokens[0].kind == TokenKind::Hash {
+                        addressing_mode = AddressingMode::Immediate;
+                        let value_tokens = &data_tokens[1..];
+                        let has_character = value_tokens.iter().any(|token| {
+                            matches!(&token.kind, TokenKind::Number(literal) if parse_char(literal).is_some())
+                        });
+                        let cannot_parse =
+                            |err_str| format!("Immediate value cannot be parsed: {}", err_str);
+                        linked_data = if has_character {
+                            // Characters are left to the character map, only the syntax is checked
+                            evaluate_with(value_tokens, &|_| Ok(0)).map_err(cannot_parse)?;
+                            InstructionLinkedData::Characters(value_tokens.to_vec())
                         } else {
-                            return Err("Immediate value cannot be parsed".to_string());
-                        }
-                    } else if let Some(value) =
-                        parse_number::<u16>(data_trimmed.replace("#", "").as_str())
-                    {
-                        addressing_mode = AddressingMode::Relative;
-                        linked_data = InstructionLinkedData::Relative(value);
+                            InstructionLinkedData::Immediate(
+                                parse_constant::<u8>(value_tokens).map_err(cannot_parse)?,
+                            )
+                        };
                     } else {
                         addressing_mode = AddressingMode::Relative;
-                        linked_data = InstructionLinkedData::NotResolvedRelative(
-                            data_str.trim_ascii().to_string(),
-                        );
+                        linked_data = parse_address(data_tokens)?;
                     }
                     Ok(Some(Instruction {
                         opcode: Opcode::Load,
-                        data: InstructionData::Registers(register.unwrap()),
+                        data: InstructionData::Registers(register),
                         size: match addressing_mode {
                             AddressingMode::Relative => 3,
                             AddressingMode::Immediate => 2,
@@ -166,36 +212,14 @@ impl Instruction {
                     return Err("Data part of instruction is none".to_string());
                 }
 
-                if let Some((register_str_1, register_str_2)) = data.unwrap().split_once(",") {
-                    let register_1: Option<Registers> = match register_str_1 {
-                        "rx" => Some(Registers::Rx),
-                        "ry" => Some(Registers::Ry),
-                        "ra" => Some(Registers::Ra),
-                        "rb" => Some(Registers::Rb),
-                        _ => None,
-                    };
-                    if register_1.is_none() {
-                        return Err(format!("Unknow register: {}", register_str_1));
-                    }
-
-                    let register_2: Option<Registers> = match register_str_2 {
-                        "rx" => Some(Registers::Rx),
-                        "ry" => Some(Registers::Ry),
-                        "ra" => Some(Registers::Ra),
-                        "rb" => Some(Registers::Rb),
-                        _ => None,
-                    };
-                    if register_2.is_none() {
-                        return Err(format!("Unknow register: {}", register_str_2));
-                    }
+                if let [register_tokens_1, register_tokens_2] = data.unwrap().as_slice() {
+                    let register_1 = parse_register(register_tokens_1)?;
+                    let register_2 = parse_register(register_tokens_2)?;
 
                     Ok(Some(Instruction {
                         opcode: Opcode::Transfer,
                         addressing_mode: AddressingMode::Immediate,
-                        data: InstructionData::DoubleRegisters(
-                            register_1.unwrap(),
-                            register_2.unwrap(),
-                        ),
+                        data: InstructionData::DoubleRegisters(register_1, register_2),
                         size: 1,
                         linked_data: None,
                     }))
@@ -208,93 +232,39 @@ impl Instruction {
                     return Err("Data part of instruction is none".to_string());
                 }
 
-                if let Some((register_str, data_str)) = data.unwrap().split_once(",") {
-                    let register: Option<Registers> = match register_str {
-                        "rx" => Some(Registers::Rx),
-                        "ry" => Some(Registers::Ry),
-                        "ra" => Some(Registers::Ra),
-                        "rb" => Some(Registers::Rb),
-                        _ => None,
-                    };
-                    if register.is_none() {
-                        return Err(format!("Unknow register: {}", register_str));
-                    }
-                    if data_str.is_empty() {
+                if let [register_tokens, data_tokens] = data.unwrap().as_slice() {
+                    let register = parse_register(register_tokens)?;
+                    if data_tokens.is_empty() {
                         return Err("Value of address is none".to_string());
                     }
-
-                    let addressing_mode;
-                    let linked_data;
-                    let data_trimmed = data_str.trim_ascii().to_string();
-                    if data_trimmed.starts_with("#") {
+                    if data_tokens[0].kind == TokenKind::Hash {
                         return Err("Store cannot be immediate, expect address".to_string());
-                    } else if let Some(value) =
-                        parse_number::<u16>(data_trimmed.replace("#", "").as_str())
-                    {
-                        addressing_mode = AddressingMode::Relative;
-                        linked_data = InstructionLinkedData::Relative(value);
-                    } else {
-                        addressing_mode = AddressingMode::Relative;
-                        linked_data = InstructionLinkedData::NotResolvedRelative(
-                            data_str.trim_ascii().to_string(),
-                        );
                     }
+
                     Ok(Some(Instruction {
                         opcode: Opcode::Store,
-                        data: InstructionData::Registers(register.unwrap()),
+                        data: InstructionData::Registers(register),
                         size: 3,
-                        linked_data: Some(linked_data),
-                        addressing_mode,
+                        linked_data: Some(parse_address(data_tokens)?),
+                        addressing_mode: AddressingMode::Relative,
                     }))
                 } else {
                     Err("format STORE <reg>,<address> is not matched!".to_string())
                 }
             }
-            "push" => {
+            "push" | "pull" => {
                 if data.is_none() {
                     return Err("Data part of instruction is none".to_string());
                 }
 
-                let register_str = data.unwrap();
-                let register: Option<Registers> = match register_str {
-                    "rx" => Some(Registers::Rx),
-                    "ry" => Some(Registers::Ry),
-                    "ra" => Some(Registers::Ra),
-                    "rb" => Some(Registers::Rb),
-                    _ => None,
-                };
-                if register.is_none() {
-                    return Err(format!("Unknow register: {}", register_str));
-                }
-
+                let register = parse_register(&tokens[1..])?;
                 Ok(Some(Instruction {
-                    opcode: Opcode::Push,
-                    data: InstructionData::Registers(register.unwrap()),
-                    size: 1,
-                    linked_data: None,
-                    addressing_mode: AddressingMode::Immediate,
-                }))
-            }
-            "pull" => {
-                if data.is_none() {
-                    return Err("Data part of instruction is none".to_string());
-                }
-
-                let register_str = data.unwrap();
-                let register: Option<Registers> = match register_str {
-                    "rx" => Some(Registers::Rx),
-                    "ry" => Some(Registers::Ry),
-                    "ra" => Some(Registers::Ra),
-                    "rb" => Some(Registers::Rb),
-                    _ => None,
-                };
-                if register.is_none() {
-                    return Err(format!("Unknow register: {}", register_str));
-                }
-
-                Ok(Some(Instruction {
-                    opcode: Opcode::Pull,
-                    data: InstructionData::Registers(register.unwrap()),
+                    opcode: if keyword == "push" {
+                        Opcode::Push
+                    } else {
+                        Opcode::Pull
+                    },
+                    data: InstructionData::Registers(register),
                     size: 1,
                     linked_data: None,
                     addressing_mode: AddressingMode::Immediate,
@@ -308,7 +278,7 @@ impl Instruction {
                     );
                 }
 
-                let math_op = match keyword {
+                let math_op = match keyword.as_str() {
                     "incr" => Some(MathOperand::Increment),
                     "add" => Some(MathOperand::Add),
                     "sub" => Some(MathOperand::Sub),
@@ -332,25 +302,12 @@ impl Instruction {
                 if data.is_none() {
                     return Err("Data part of instruction is none".to_string());
                 }
-
-                let addressing_mode;
-                let linked_data;
-                let data_trimmed = data.unwrap().to_string();
-                if data_trimmed.starts_with("#") {
+                if tokens[1].kind == TokenKind::Hash {
                     return Err("Jump cannot be immediate, expect address".to_string());
-                } else if let Some(value) =
-                    parse_number::<u16>(data_trimmed.replace("#", "").as_str())
-                {
-                    addressing_mode = AddressingMode::Relative;
-                    linked_data = Some(InstructionLinkedData::Relative(value));
-                } else {
-                    addressing_mode = AddressingMode::Relative;
-                    linked_data = Some(InstructionLinkedData::NotResolvedRelative(
-                        data.unwrap().to_string(),
-                    ));
                 }
+                let linked_data = Some(parse_address(&tokens[1..])?);
 
-                let branch_condition = match keyword {
+                let branch_condition = match keyword.as_str() {
                     "jump" => Some(BranchCondition::NoCondition),
                     "bcc" => Some(BranchCondition::CarryFlagClear),
                     "bcs" => Some(BranchCondition::CarryFlagSet),
@@ -369,7 +326,7 @@ impl Instruction {
                     data: InstructionData::BranchCondition(branch_condition.unwrap()),
                     size: 3,
                     linked_data,
-                    addressing_mode,
+                    addressing_mode: AddressingMode::Relative,
                 }))
             }
             "rts" => {
@@ -408,6 +365,9 @@ impl Instruction {
                 InstructionLinkedData::Immediate(val) => {
                     bytes_vec.append(&mut val.to_le_bytes().to_vec())
                 }
+                InstructionLinkedData::Characters(tokens) => {
+                    bytes_vec.push(evaluate(tokens).unwrap_or(0) as u8)
+                }
                 InstructionLinkedData::Relative(val) => {
                     bytes_vec.append(&mut val.to_le_bytes().to_vec())
                 }
@@ -425,16 +385,41 @@ impl Instruction {
 mod tests {
     use super::*;
 
+    fn parse(str: &str) -> Result<Option<Instruction>, String> {
+        Instruction::new(&tokenize(str)?)
+    }
+
     #[test]
     fn test_wrong_inst() {
-        let inst = Instruction::new("azerty");
+        let inst = parse("azerty");
         assert!(inst.is_ok());
         assert!(inst.ok().unwrap().is_none());
     }
 
+    #[test]
+    fn test_whitespace_and_case() {
+        for str in ["load  rx,$12", "load rx, $12", "load\trx ,\t$12", "LOAD RX,$12"] {
+            let inst = parse(str);
+            assert!(inst.is_ok());
+            let inst = inst.ok().unwrap().unwrap();
+            assert_eq!(inst.to_bytes(), vec![0b00011001, 0x12, 0]);
+        }
+
+        let inst = parse("Tf Ra, rB");
+        assert!(inst.is_ok());
+        assert_eq!(inst.ok().unwrap().unwrap().to_bytes(), vec![0b11000010]);
+
+        let inst = parse("load rx,# 5");
+        assert!(inst.is_ok());
+        assert_eq!(inst.ok().unwrap().unwrap().to_bytes(), vec![0b00010001, 5]);
+
+        let inst = parse("load rx,$12 $13");
+        assert!(inst.is_err());
+    }
+
     #[test]
     fn test_halt() {
-        let inst = Instruction::new("halt");
+        let inst = parse("halt");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0]);
@@ -443,70 +428,91 @@ mod tests {
 
     #[test]
     fn test_load() {
-        let inst = Instruction::new("load");
+        let inst = parse("load");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("load aze");
+        let inst = parse("load aze");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("load rz,#300");
+        let inst = parse("load rz,#300");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("load rx,");
+        let inst = parse("load rx,");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("load rx,#300");
+        let inst = parse("load rx,#300");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("load ra,#5");
+        let inst = parse("load ra,#5");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![1, 5]);
         assert_eq!(inst.size, 2);
 
-        let inst = Instruction::new("load ra,$abac");
+        let inst = parse("load ra,$abac");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b1001, 0xac, 0xab]);
         assert_eq!(inst.size, 3);
 
-        let inst = Instruction::new("load ra,flag_test");
+        let inst = parse("load ra,flag_test");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b1001, 0, 0]);
         assert_eq!(inst.size, 3);
+
+        // Signed immediates and constant expressions
+        let inst = parse("load rb,#-1").unwrap().unwrap();
+        assert_eq!(inst.to_bytes(), vec![0b110001, 0xFF]);
+        let inst = parse("load ra,#'a' - 'A'").unwrap().unwrap();
+        assert_eq!(inst.to_bytes(), vec![1, 32]);
+        assert!(parse("load ra,#-129").is_err());
+        assert!(parse("load ra,#5 +").is_err());
+
+        // Characters are left to the character map
+        let inst = parse("load ra,#'°'").unwrap().unwrap();
+        assert!(matches!(inst.linked_data, Some(InstructionLinkedData::Characters(_))));
+        let inst = parse("load ra,#'a' - 'A'").unwrap().unwrap();
+        assert!(matches!(inst.linked_data, Some(InstructionLinkedData::Characters(_))));
+        assert!(parse("load ra,#'a' 'A'").is_err());
+
+        let inst = parse("load ra,$8000 + 2").unwrap().unwrap();
+        assert_eq!(inst.to_bytes(), vec![0b1001, 2, 0x80]);
+        let inst = parse("load ra,-2").unwrap().unwrap();
+        assert_eq!(inst.to_bytes(), vec![0b1001, 0xFE, 0xFF]);
+        assert!(parse("load ra,$FFFF + 1").is_err());
     }
 
     #[test]
     fn test_transfer() {
-        let inst = Instruction::new("tf");
+        let inst = parse("tf");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("tf aze");
+        let inst = parse("tf aze");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("tf rx,");
+        let inst = parse("tf rx,");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("tf rx,rz");
+        let inst = parse("tf rx,rz");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("tf rz,rx");
+        let inst = parse("tf rz,rx");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("tf rx,ry");
+        let inst = parse("tf rx,ry");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b10010010]);
         assert_eq!(inst.size, 1);
 
-        let inst = Instruction::new("tf ra,rb");
+        let inst = parse("tf ra,rb");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b11000010]);
         assert_eq!(inst.size, 1);
 
-        let inst = Instruction::new("tf ra,ra");
+        let inst = parse("tf ra,ra");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b00000010]);
@@ -515,28 +521,28 @@ mod tests {
 
     #[test]
     fn test_store() {
-        let inst = Instruction::new("store");
+        let inst = parse("store");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("store rx,");
+        let inst = parse("store rx,");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("store rx,#5");
+        let inst = parse("store rx,#5");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("store rz,#5");
+        let inst = parse("store rz,#5");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("store rz,flag_test");
+        let inst = parse("store rz,flag_test");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("store ra,flag_test");
+        let inst = parse("store ra,flag_test");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b00001011, 0, 0]);
         assert_eq!(inst.size, 3);
 
-        let inst = Instruction::new("store ra,$abac");
+        let inst = parse("store ra,$abac");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b00001011, 0xac, 0xab]);
@@ -545,34 +551,34 @@ mod tests {
 
     #[test]
     fn test_push() {
-        let inst = Instruction::new("push");
+        let inst = parse("push");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("push rz");
+        let inst = parse("push rz");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("push #5");
+        let inst = parse("push #5");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("push ra");
+        let inst = parse("push ra");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b00000100]);
         assert_eq!(inst.size, 1);
 
-        let inst = Instruction::new("push rx");
+        let inst = parse("push rx");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b00010100]);
         assert_eq!(inst.size, 1);
 
-        let inst = Instruction::new("push ry");
+        let inst = parse("push ry");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b00100100]);
         assert_eq!(inst.size, 1);
 
-        let inst = Instruction::new("push rb");
+        let inst = parse("push rb");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b00110100]);
@@ -581,34 +587,34 @@ mod tests {
 
     #[test]
     fn test_pull() {
-        let inst = Instruction::new("pull");
+        let inst = parse("pull");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("pull rz");
+        let inst = parse("pull rz");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("pull #5");
+        let inst = parse("pull #5");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("pull ra");
+        let inst = parse("pull ra");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b00000101]);
         assert_eq!(inst.size, 1);
 
-        let inst = Instruction::new("pull rx");
+        let inst = parse("pull rx");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b00010101]);
         assert_eq!(inst.size, 1);
 
-        let inst = Instruction::new("pull ry");
+        let inst = parse("pull ry");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b00100101]);
         assert_eq!(inst.size, 1);
 
-        let inst = Instruction::new("pull rb");
+        let inst = parse("pull rb");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b00110101]);
@@ -617,52 +623,52 @@ mod tests {
 
     #[test]
     fn test_math() {
-        let inst = Instruction::new("add ra");
+        let inst = parse("add ra");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("incr");
+        let inst = parse("incr");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b00000110]);
         assert_eq!(inst.size, 1);
 
-        let inst = Instruction::new("add");
+        let inst = parse("add");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b00010110]);
         assert_eq!(inst.size, 1);
 
-        let inst = Instruction::new("sub");
+        let inst = parse("sub");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b00100110]);
         assert_eq!(inst.size, 1);
 
-        let inst = Instruction::new("and");
+        let inst = parse("and");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b00110110]);
         assert_eq!(inst.size, 1);
 
-        let inst = Instruction::new("or");
+        let inst = parse("or");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b01000110]);
         assert_eq!(inst.size, 1);
 
-        let inst = Instruction::new("eor");
+        let inst = parse("eor");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b01010110]);
         assert_eq!(inst.size, 1);
 
-        let inst = Instruction::new("shift_left");
+        let inst = parse("shift_left");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b01100110]);
         assert_eq!(inst.size, 1);
 
-        let inst = Instruction::new("shift_right");
+        let inst = parse("shift_right");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b01110110]);
@@ -671,82 +677,92 @@ mod tests {
 
     #[test]
     fn test_jump() {
-        let inst = Instruction::new("jump");
+        let inst = parse("jump");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("jump #5");
+        let inst = parse("jump #5");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("jump $abac");
+        let inst = parse("jump $abac");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b00001111, 0xac, 0xab]);
         assert_eq!(inst.size, 3);
 
-        let inst = Instruction::new("jump test");
+        let inst = parse("jump test");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b00001111, 0, 0]);
         assert_eq!(inst.size, 3);
 
-        let inst = Instruction::new("bcc test");
+        let inst = parse("bcc test");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b00011111, 0, 0]);
         assert_eq!(inst.size, 3);
 
-        let inst = Instruction::new("bcs test");
+        let inst = parse("bcs test");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b00101111, 0, 0]);
         assert_eq!(inst.size, 3);
 
-        let inst = Instruction::new("bzc test");
+        let inst = parse("bzc test");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b00111111, 0, 0]);
         assert_eq!(inst.size, 3);
 
-        let inst = Instruction::new("bzs test");
+        let inst = parse("bzs test");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b01001111, 0, 0]);
         assert_eq!(inst.size, 3);
 
-        let inst = Instruction::new("bnc test");
+        let inst = parse("bnc test");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b01011111, 0, 0]);
         assert_eq!(inst.size, 3);
 
-        let inst = Instruction::new("bns test");
+        let inst = parse("bns test");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b01101111, 0, 0]);
         assert_eq!(inst.size, 3);
 
-        let inst = Instruction::new("boc test");
+        let inst = parse("boc test");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b01111111, 0, 0]);
         assert_eq!(inst.size, 3);
 
-        let inst = Instruction::new("bos test");
+        let inst = parse("bos test");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b10001111, 0, 0]);
         assert_eq!(inst.size, 3);
 
-        let inst = Instruction::new("jsr test");
+        let inst = parse("jsr test");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b10011111, 0, 0]);
         assert_eq!(inst.size, 3);
 
-        let inst = Instruction::new("rts $0000");
+        let inst = parse("bcc :--");
+        assert!(inst.is_ok());
+        let inst = inst.ok().unwrap().unwrap();
+        assert!(matches!(inst.linked_data, Some(InstructionLinkedData::NotResolvedRelative(ref l)) if l == ":--"));
+
+        let inst = parse("jsr .sub");
+        assert!(inst.is_ok());
+        let inst = inst.ok().unwrap().unwrap();
+        assert!(matches!(inst.linked_data, Some(InstructionLinkedData::NotResolvedRelative(ref l)) if l == "@sub"));
+
+        let inst = parse("rts $0000");
         assert!(inst.is_err());
 
-        let inst = Instruction::new("rts");
+        let inst = parse("rts");
         assert!(inst.is_ok());
         let inst = inst.ok().unwrap().unwrap();
         assert_eq!(inst.to_bytes(), vec![0b10100111]);
@@ -1,38 +1,284 @@
-use regex::Regex;
+use super::token::*;
 
+pub const ANONYMOUS_LABEL: &str = ":";
+pub const LOCAL_LABEL_PREFIX: char = '@';
+pub const NAMESPACE_SEPARATOR: &str = "::";
+
+#[derive(Clone)]
 pub struct Label {
     pub name: String,
 }
 impl Label {
-    pub fn new(str: &str) -> Result<Option<Self>, String> {
-        let re = Regex::new(r"^[a-z_0-9]+:").unwrap();
-        if re.captures(str).is_some() {
-            let name = str.split(":").collect::<Vec<&str>>()[0].to_string();
-            return Ok(Some(Label { name }));
+    /// Global labels are `name:`, local labels `@name:` or `.name:` (named `@name`)
+    /// and anonymous labels a single `:`.
+    pub fn new(tokens: &[Token]) -> Result<Option<Self>, String> {
+        let name = match tokens {
+            [name, colon] if colon.kind == TokenKind::Colon && is_adjacent(tokens) => {
+                name.identifier().map(|name| name.to_string())
+            }
+            [prefix, name, colon]
+                if matches!(prefix.kind, TokenKind::At | TokenKind::Dot)
+                    && colon.kind == TokenKind::Colon
+                    && is_adjacent(tokens) =>
+            {
+                name.identifier()
+                    .map(|name| format!("{}{}", LOCAL_LABEL_PREFIX, name))
+            }
+            [colon] if colon.kind == TokenKind::Colon => Some(ANONYMOUS_LABEL.to_string()),
+            _ => None,
+        };
+        Ok(name.map(|name| Label { name }))
+    }
+
+    /// Split a label from the start of a statement, returning it with the number
+    /// of tokens it uses.
+    pub fn split_prefix(tokens: &[Token]) -> Result<Option<(Self, usize)>, String> {
+        for length in (1..=3).rev() {
+            if length > tokens.len() {
+                continue;
+            }
+            // `:-` and `:+` are references to anonymous labels, not definitions
+            if matches!(
+                tokens.get(length).map(|t| &t.kind),
+                Some(TokenKind::Plus | TokenKind::Minus)
+            ) {
+                continue;
+            }
+            if let Some(label) = Label::new(&tokens[..length])? {
+                return Ok(Some((label, length)));
+            }
         }
         Ok(None)
     }
+
+    pub fn is_local(&self) -> bool {
+        self.name.starts_with(LOCAL_LABEL_PREFIX)
+    }
+
+    pub fn is_anonymous(&self) -> bool {
+        self.name == ANONYMOUS_LABEL
+    }
+}
+
+fn is_adjacent(tokens: &[Token]) -> bool {
+    tokens.windows(2).all(|w| w[0].span.end == w[1].span.start)
+}
+
+/// Parse a reference to a local (`@name`, `.name`), anonymous (`:-`, `:++`)
+/// or qualified (`math::mul8`) label.
+pub fn parse_label_reference(tokens: &[Token]) -> Option<String> {
+    match tokens {
+        [prefix, name]
+            if matches!(prefix.kind, TokenKind::At | TokenKind::Dot) && is_adjacent(tokens) =>
+        {
+            name.identifier()
+                .map(|name| format!("{}{}", LOCAL_LABEL_PREFIX, name))
+        }
+        [colon, direction, ..]
+            if colon.kind == TokenKind::Colon
+                && matches!(direction.kind, TokenKind::Plus | TokenKind::Minus)
+                && tokens[1..].iter().all(|t| t.kind == direction.kind)
+                && is_adjacent(tokens) =>
+        {
+            Some(tokens_to_string(tokens))
+        }
+        [first, rest @ ..]
+            if first.identifier().is_some() && !rest.is_empty() && is_adjacent(tokens) =>
+        {
+            let mut names = vec![first.identifier()?];
+            for chunk in rest.chunks(3) {
+                match chunk {
+                    [a, b, name] if a.kind == TokenKind::Colon && b.kind == TokenKind::Colon => {
+                        names.push(name.identifier()?)
+                    }
+                    _ => return None,
+                }
+            }
+            Some(names.join(NAMESPACE_SEPARATOR))
+        }
+        _ => None,
+    }
+}
+
+pub fn is_local_reference(name: &str) -> bool {
+    name.starts_with(LOCAL_LABEL_PREFIX)
+}
+
+pub fn is_anonymous_reference(name: &str) -> bool {
+    name.starts_with(ANONYMOUS_LABEL)
+}
+
+/// Attach a local label to the global label `scope` it belongs to. `tokens` are the ones
+/// the label was lexed from, to report it as it was written.
+pub fn qualify_label(scope: Option<&str>, name: &str, tokens: &[Token]) -> Result<String, String> {
+    if !is_local_reference(name) {
+        return Ok(name.to_string());
+    }
+    match scope {
+        Some(scope) => Ok(format!("{}{}", scope, name)),
+        None => Err(format!(
+            "Local label {} is used before any global label",
+            written_name(tokens, name)
+        )),
+    }
+}
+
+/// Local label `name` as written in `tokens`, `.name` or `@name`.
+fn written_name(tokens: &[Token], name: &str) -> String {
+    let identifier = name.trim_start_matches(LOCAL_LABEL_PREFIX);
+    tokens
+        .windows(2)
+        .find(|pair| {
+            matches!(pair[0].kind, TokenKind::At | TokenKind::Dot)
+                && pair[1].identifier() == Some(identifier)
+                && is_adjacent(pair)
+        })
+        .map(tokens_to_string)
+        .unwrap_or(name.to_string())
+}
+
+/// Prefix a label name with the scopes it is defined in, e.g. `math::mul8`.
+pub fn scoped_name(scopes: &[String], name: &str) -> String {
+    scopes
+        .iter()
+        .map(|scope| scope.as_str())
+        .chain([name])
+        .collect::<Vec<_>>()
+        .join(NAMESPACE_SEPARATOR)
+}
+
+/// Names a reference made inside `scopes` can resolve to, innermost scope first.
+pub fn scoped_candidates(scopes: &[String], name: &str) -> Vec<String> {
+    (0..=scopes.len())
+        .rev()
+        .map(|depth| scoped_name(&scopes[..depth], name))
+        .collect()
+}
+
+/// Resolve `:-`/`:+` style references given the addresses of the anonymous labels
+/// of a file and how many of them are defined before the reference.
+pub fn resolve_anonymous_label<T: Clone>(
+    reference: &str,
+    defined_before: usize,
+    anonymous_labels: &[T],
+) -> Result<T, String> {
+    let count = reference.len() - 1;
+    let index = if reference.ends_with('-') {
+        defined_before.checked_sub(count)
+    } else {
+        Some(defined_before + count - 1)
+    };
+
+    index
+        .and_then(|index| anonymous_labels.get(index))
+        .cloned()
+        .ok_or(format!(
+            "Anonymous label reference {} has no target",
+            reference
+        ))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn parse(str: &str) -> Result<Option<Label>, String> {
+        Label::new(&tokenize(str)?)
+    }
+
     #[test]
     fn test_label() {
-        let new_instance = Label::new("label_test_2:");
+        let new_instance = parse("label_test_2:");
 
         assert!(new_instance.is_ok());
         assert_eq!(new_instance.unwrap().unwrap().name, "label_test_2");
 
-        let new_instance = Label::new("halt");
+        let new_instance = parse("halt");
 
         assert!(new_instance.is_ok());
         assert!(new_instance.unwrap().is_none());
 
-        let new_instance = Label::new(":");
+        let new_instance = parse(":");
+
+        assert!(new_instance.is_ok());
+        assert!(new_instance.unwrap().unwrap().is_anonymous());
+
+        let new_instance = parse("label :");
 
         assert!(new_instance.is_ok());
         assert!(new_instance.unwrap().is_none());
-    }    
+    }
+
+    #[test]
+    fn test_local_label() {
+        for str in ["@loop:", ".loop:"] {
+            let new_instance = parse(str);
+            assert!(new_instance.is_ok());
+            let label = new_instance.unwrap().unwrap();
+            assert_eq!(label.name, "@loop");
+            assert!(label.is_local());
+        }
+
+        let tokens = tokenize("@loop: incr").unwrap();
+        let (label, length) = Label::split_prefix(&tokens).unwrap().unwrap();
+        assert_eq!((label.name.as_str(), length), ("@loop", 3));
+
+        let tokens = tokenize(":- incr").unwrap();
+        assert!(Label::split_prefix(&tokens).unwrap().is_none());
+
+        assert_eq!(
+            qualify_label(Some("start"), "@loop", &[]).unwrap(),
+            "start@loop"
+        );
+        assert_eq!(qualify_label(None, "start", &[]).unwrap(), "start");
+        assert!(qualify_label(None, "@loop", &[]).is_err());
+        // Errors name the label as written
+        let tokens = tokenize(".loop: halt").unwrap();
+        assert_eq!(
+            qualify_label(None, "@loop", &tokens).unwrap_err(),
+            "Local label .loop is used before any global label"
+        );
+    }
+
+    #[test]
+    fn test_scoped_label() {
+        let scopes = ["math".to_string(), "mul".to_string()];
+        assert_eq!(scoped_name(&scopes, "loop"), "math::mul::loop");
+        assert_eq!(scoped_name(&[], "loop"), "loop");
+        assert_eq!(
+            scoped_candidates(&scopes, "loop"),
+            vec!["math::mul::loop", "math::loop", "loop"]
+        );
+    }
+
+    #[test]
+    fn test_label_reference() {
+        let reference = |str| parse_label_reference(&tokenize(str).unwrap());
+        assert_eq!(reference(".loop"), Some("@loop".to_string()));
+        assert_eq!(reference("@loop"), Some("@loop".to_string()));
+        assert_eq!(reference(":--"), Some(":--".to_string()));
+        assert_eq!(reference(":+"), Some(":+".to_string()));
+        assert_eq!(reference(":+-"), None);
+        assert_eq!(reference("@ loop"), None);
+        assert_eq!(reference("math::mul8"), Some("math::mul8".to_string()));
+        assert_eq!(reference("a::b::c"), Some("a::b::c".to_string()));
+        assert_eq!(reference("math:mul8"), None);
+        assert_eq!(reference("math ::mul8"), None);
+
+        let anonymous_labels = [0x10, 0x20, 0x30];
+        assert_eq!(
+            resolve_anonymous_label(":-", 2, &anonymous_labels),
+            Ok(0x20)
+        );
+        assert_eq!(
+            resolve_anonymous_label(":--", 2, &anonymous_labels),
+            Ok(0x10)
+        );
+        assert_eq!(
+            resolve_anonymous_label(":+", 2, &anonymous_labels),
+            Ok(0x30)
+        );
+        assert!(resolve_anonymous_label(":++", 2, &anonymous_labels).is_err());
+        assert!(resolve_anonymous_label(":---", 2, &anonymous_labels).is_err());
+    }
 }
@@ -1,40 +1,154 @@
 mod generic;
+mod expression;
 mod instruction;
 mod flag;
 mod label;
+mod token;
 
 pub use instruction::*;
 pub use label::*;
 pub use flag::*;
+pub use token::*;
 
-use generic::trim_line;
+pub use expression::{evaluate, evaluate_with, parse_constant, parse_constant_with};
+pub use generic::{parse_char, parse_number, trim_line, try_parse_number, unescape};
 
+use std::ops::Range;
+
+#[derive(Clone)]
 pub enum TokenType {
     Instruction(Instruction),
     Flag(Flag),
     Label(Label),
 }
 
-pub fn lex_line(line: &str) -> Result<Option<TokenType>, String> {
-    let line = trim_line(line);
-    if line.is_empty() {
-        return Ok(None);
+/// Token type with the tokens it was lexed from.
+#[derive(Clone)]
+pub struct LexedToken {
+    pub token_type: TokenType,
+    pub tokens: Vec<Token>,
+}
+
+/// Error lexing a line, with the byte range of the statement it is in or of the whole line.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LexError {
+    pub message: String,
+    pub span: Range<usize>,
+}
+
+/// Split the tokens of a line on statement separators.
+pub fn split_statements(tokens: &[Token]) -> Vec<&[Token]> {
+    tokens
+        .split(|t| t.kind == TokenKind::Separator)
+        .collect()
+}
+
+/// Lex one statement: any number of labels followed by an optional flag or instruction.
+/// Each token type is returned with the tokens it was lexed from.
+pub fn lex_statement(tokens: &[Token]) -> Result<Vec<(TokenType, &[Token])>, String> {
+    let mut tokens = tokens;
+    let mut token_types = vec![];
+    while let Some((label, length)) = Label::split_prefix(tokens)? {
+        token_types.push((TokenType::Label(label), &tokens[..length]));
+        tokens = &tokens[length..];
     }
 
-    let flag_opt = Flag::new(line)?;
-    if let Some(flag) = flag_opt {
-        return Ok(Some(TokenType::Flag(flag)));
+    if tokens.is_empty() {
+        if token_types.is_empty() {
+            return Err("Statement is empty".to_string());
+        }
+        return Ok(token_types);
     }
 
-    let instruction_opt = Instruction::new(line)?;
-    if let Some(instruction) = instruction_opt {
-        return Ok(Some(TokenType::Instruction(instruction)));
+    if let Some(flag) = Flag::new(tokens)? {
+        token_types.push((TokenType::Flag(flag), tokens));
+    } else if let Some(instruction) = Instruction::new(tokens)? {
+        token_types.push((TokenType::Instruction(instruction), tokens));
+    } else {
+        return Err("Line cannot be lex".to_string());
     }
-    
-    let label_opt = Label::new(line)?;
-    if let Some(label) = label_opt {
-        return Ok(Some(TokenType::Label(label)))
+    Ok(token_types)
+}
+
+pub fn lex_line(line: &str) -> Result<Vec<TokenType>, String> {
+    lex_line_tokens(line)
+        .map(|lexed| lexed.into_iter().map(|l| l.token_type).collect())
+        .map_err(|error| error.message)
+}
+
+/// Lex a line like `lex_line`, keeping the tokens of each token type to point at them.
+pub fn lex_line_tokens(line: &str) -> Result<Vec<LexedToken>, LexError> {
+    let line_span = || {
+        let indent = line.len() - line.trim_start().len();
+        indent..indent + trim_line(line).len()
+    };
+    let mut tokens = tokenize(line).map_err(|message| LexError {
+        message,
+        span: line_span(),
+    })?;
+    tokens.retain(|t| !matches!(t.kind, TokenKind::Comment(_)));
+    if tokens.is_empty() {
+        return Ok(vec![]);
     }
 
-    Err("Line cannot be lex".to_string())
+    let mut lexed = vec![];
+    for statement in split_statements(&tokens) {
+        let token_types = lex_statement(statement).map_err(|message| LexError {
+            message,
+            span: match (statement.first(), statement.last()) {
+                (Some(first), Some(last)) => first.span.start..last.span.end,
+                _ => line_span(),
+            },
+        })?;
+        lexed.extend(
+            token_types
+                .into_iter()
+                .map(|(token_type, tokens)| LexedToken {
+                    token_type,
+                    tokens: tokens.to_vec(),
+                }),
+        );
+    }
+    Ok(lexed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lex_line() {
+        let token_types = lex_line("loop: incr ; comment").unwrap();
+        assert_eq!(token_types.len(), 2);
+        assert!(matches!(&token_types[0], TokenType::Label(label) if label.name == "loop"));
+        assert!(matches!(&token_types[1], TokenType::Instruction(inst) if inst.size == 1));
+
+        let token_types = lex_line("load ra,#1 \\ add \\ end: done: halt").unwrap();
+        assert_eq!(token_types.len(), 5);
+        assert!(matches!(&token_types[3], TokenType::Label(label) if label.name == "done"));
+
+        let token_types = lex_line("sub: .org $10").unwrap();
+        assert!(matches!(&token_types[1], TokenType::Flag(Flag::Org(16))));
+
+        assert!(lex_line("; comment").unwrap().is_empty());
+        assert!(lex_line("halt \\").is_err());
+        assert!(lex_line("loop: bad").is_err());
+        assert!(lex_line("label :").is_err());
+
+        let token_types = lex_line(": @loop: .end: jump :-").unwrap();
+        assert_eq!(token_types.len(), 4);
+        assert!(matches!(&token_types[0], TokenType::Label(label) if label.is_anonymous()));
+        assert!(matches!(&token_types[2], TokenType::Label(label) if label.name == "@end"));
+        assert!(matches!(&token_types[3], TokenType::Instruction(_)));
+
+        let lexed = lex_line_tokens("  loop: incr \\ jump loop").unwrap();
+        assert_eq!(lexed[2].tokens.first().unwrap().span, 15..19);
+        assert_eq!(
+            lex_line_tokens("  incr \\ bad ; comment").err(),
+            Some(LexError {
+                message: "Line cannot be lex".to_string(),
+                span: 9..12
+            })
+        );
+    }
 }
@@ -0,0 +1,191 @@
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum TokenKind {
+    Identifier(String),
+    Number(String),
+    String(String),
+    Comma,
+    Colon,
+    Hash,
+    Dot,
+    At,
+    Plus,
+    Minus,
+    Separator,
+    Comment(String),
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenKind::Identifier(str) | TokenKind::Number(str) => write!(f, "{}", str),
+            TokenKind::String(str) => write!(f, "\"{}\"", str),
+            TokenKind::Comma => write!(f, ","),
+            TokenKind::Colon => write!(f, ":"),
+            TokenKind::Hash => write!(f, "#"),
+            TokenKind::Dot => write!(f, "."),
+            TokenKind::At => write!(f, "@"),
+            TokenKind::Plus => write!(f, "+"),
+            TokenKind::Minus => write!(f, "-"),
+            TokenKind::Separator => write!(f, "\\"),
+            TokenKind::Comment(str) => write!(f, "{}", str),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    /// Byte range of the token in the line
+    pub span: Range<usize>,
+}
+
+impl Token {
+    pub fn identifier(&self) -> Option<&str> {
+        match &self.kind {
+            TokenKind::Identifier(str) => Some(str),
+            _ => None,
+        }
+    }
+}
+
+/// Render tokens back to source text, used in error messages.
+pub fn tokens_to_string(tokens: &[Token]) -> String {
+    tokens.iter().map(|t| t.kind.to_string()).collect()
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Consume the characters up to the unescaped `quote` closing a literal and return its index.
+fn find_closing(chars: &mut impl Iterator<Item = (usize, char)>, quote: char) -> Option<usize> {
+    let mut escaped = false;
+    let (idx, _) = chars.find(|(_, c)| {
+        let is_closing = *c == quote && !escaped;
+        escaped = *c == '\\' && !escaped;
+        is_closing
+    })?;
+    Some(idx)
+}
+
+pub fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = line.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        let mut end = start + c.len_utf8();
+        let mut take_word = |end: &mut usize| {
+            while let Some((idx, c)) = chars.next_if(|(_, c)| is_word_char(*c)) {
+                *end = idx + c.len_utf8();
+            }
+        };
+
+        let kind = match c {
+            _ if c.is_whitespace() => continue,
+            ';' => {
+                end = line.len();
+                TokenKind::Comment(line[start..].to_string())
+            }
+            ',' => TokenKind::Comma,
+            ':' => TokenKind::Colon,
+            '#' => TokenKind::Hash,
+            '.' => TokenKind::Dot,
+            '@' => TokenKind::At,
+            '+' => TokenKind::Plus,
+            '-' => TokenKind::Minus,
+            '\\' => TokenKind::Separator,
+            // Escape sequences are kept, they are replaced by the flags using the string
+            '"' => match find_closing(&mut chars, '"') {
+                Some(idx) => {
+                    end = idx + 1;
+                    TokenKind::String(line[start + 1..idx].to_string())
+                }
+                None => return Err("String is not terminated".to_string()),
+            },
+            '$' | '%' | '0'..='9' => {
+                take_word(&mut end);
+                TokenKind::Number(line[start..end].to_string())
+            }
+            // Character literals are numbers, the quote can be escaped
+            '\'' => match find_closing(&mut chars, '\'') {
+                Some(idx) => {
+                    end = idx + 1;
+                    TokenKind::Number(line[start..end].to_string())
+                }
+                None => return Err("Character is not terminated".to_string()),
+            },
+            _ if c.is_ascii_alphabetic() || c == '_' => {
+                take_word(&mut end);
+                TokenKind::Identifier(line[start..end].to_string())
+            }
+            _ => return Err(format!("Unexpected character: {}", c)),
+        };
+
+        let is_comment = matches!(kind, TokenKind::Comment(_));
+        tokens.push(Token {
+            kind,
+            span: start..end,
+        });
+        if is_comment {
+            break;
+        }
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        let tokens = tokenize("  LOAD\trx, #$1F ; comment").unwrap();
+        assert_eq!(
+            tokens.iter().map(|t| t.kind.clone()).collect::<Vec<_>>(),
+            vec![
+                TokenKind::Identifier("LOAD".to_string()),
+                TokenKind::Identifier("rx".to_string()),
+                TokenKind::Comma,
+                TokenKind::Hash,
+                TokenKind::Number("$1F".to_string()),
+                TokenKind::Comment("; comment".to_string()),
+            ]
+        );
+        assert_eq!(tokens[0].span, 2..6);
+        assert_eq!(tokens[4].span, 12..15);
+
+        let tokens = tokenize(".include \"lib; a.tasm\"").unwrap();
+        assert_eq!(tokens[2].kind, TokenKind::String("lib; a.tasm".to_string()));
+        assert_eq!(tokens[2].span, 9..22);
+        assert_eq!(tokens.len(), 3);
+
+        let tokens = tokenize("loop: incr \\ halt").unwrap();
+        assert_eq!(tokens[3].kind, TokenKind::Separator);
+        assert_eq!(tokens_to_string(&tokens), "loop:incr\\halt");
+
+        let tokens = tokenize("load ra,#'\\'' \\ load rb,#%1010_0101").unwrap();
+        assert_eq!(tokens[4].kind, TokenKind::Number("'\\''".to_string()));
+        assert_eq!(tokens[10].kind, TokenKind::Number("%1010_0101".to_string()));
+        assert_eq!(
+            tokens_to_string(&tokens),
+            "loadra,#'\\''\\loadrb,#%1010_0101"
+        );
+
+        let tokens = tokenize(".ascii \"say \\\"hi\\\"\" ; x").unwrap();
+        assert_eq!(
+            tokens[2].kind,
+            TokenKind::String("say \\\"hi\\\"".to_string())
+        );
+        assert_eq!(tokens_to_string(&tokens[..3]), ".ascii\"say \\\"hi\\\"\"");
+
+        assert!(tokenize("\"unterminated").is_err());
+        assert!(tokenize("\"escaped\\\"").is_err());
+        assert!(tokenize("'a").is_err());
+        assert!(tokenize("wr?ong").is_err());
+        assert!(tokenize("").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,309 @@
+use std::collections::HashMap;
+
+pub const DEFAULT_LINKER_SCRIPT_PATH: &str = "tasm-link.toml";
+
+pub struct MemoryRegion {
+    pub name: String,
+    pub start: u16,
+    pub size: u32,
+}
+
+pub struct SectionPlacement {
+    pub name: String,
+    /// Memory region where the bytes of the section are stored
+    pub load: String,
+    /// Memory region where the section is used at run time, same as `load` if not set
+    pub run: Option<String>,
+}
+
+/// Placement of sections in memory regions, read from a linker script.
+/// Without a script sections are placed one after the other after the absolute code.
+#[derive(Default)]
+pub struct LinkerConfig {
+    pub memories: Vec<MemoryRegion>,
+    pub sections: Vec<SectionPlacement>,
+}
+
+/// Size and alignment of a section once every file is parsed.
+#[derive(Clone)]
+pub struct SectionLayout {
+    pub name: String,
+    pub size: u16,
+    pub alignment: u16,
+}
+
+/// Addresses given to a section by the linker.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Placement {
+    pub load: u16,
+    pub run: u16,
+}
+
+/// Labels defined by the linker for every section.
+pub fn is_linker_symbol(name: &str) -> bool {
+    name.starts_with("__")
+        && ["_start", "_load", "_size"]
+            .iter()
+            .any(|suffix| name.ends_with(suffix))
+}
+
+fn get_string(table: &toml::Table, key: &str, kind: &str) -> Result<String, String> {
+    table
+        .get(key)
+        .and_then(|value| value.as_str())
+        .map(|value| value.to_string())
+        .ok_or(format!("{} should have a string {}", kind, key))
+}
+
+fn get_integer(table: &toml::Table, key: &str, name: &str) -> Result<i64, String> {
+    table
+        .get(key)
+        .and_then(|value| value.as_integer())
+        .ok_or(format!("Memory {} should have an integer {}", name, key))
+}
+
+fn align_up(addr: u32, alignment: u16) -> u32 {
+    addr.div_ceil(alignment as u32) * alignment as u32
+}
+
+impl LinkerConfig {
+    /// Read `[[memory]]` regions (`name`, `start`, `size`) and `[[section]]`
+    /// placements (`name`, `load` and optional `run` memory).
+    pub fn from_toml(str: &str) -> Result<Self, String> {
+        let table = str.parse::<toml::Table>().map_err(|e| e.to_string())?;
+        LinkerConfig::from_table(&table)
+    }
+
+    /// Read the `memory` and `section` arrays of a table, e.g. a target of `tasm.toml`.
+    pub fn from_table(table: &toml::Table) -> Result<Self, String> {
+        let mut config = LinkerConfig::default();
+
+        let empty = vec![];
+        let memories = match table.get("memory") {
+            Some(memories) => memories.as_array().ok_or("memory should be [[memory]]")?,
+            None => &empty,
+        };
+        for memory in memories {
+            let memory = memory.as_table().ok_or("memory should be [[memory]]")?;
+            let name = get_string(memory, "name", "Memory")?;
+            let start = get_integer(memory, "start", &name)?;
+            let size = get_integer(memory, "size", &name)?;
+            if !(0..=u16::MAX as i64).contains(&start) || size < 0 || start + size > 0x10000 {
+                return Err(format!("Memory {} is outside of the address space", name));
+            }
+            if config.memories.iter().any(|m| m.name == name) {
+                return Err(format!("Memory {} is defined twice", name));
+            }
+            config.memories.push(MemoryRegion {
+                name,
+                start: start as u16,
+                size: size as u32,
+            });
+        }
+
+        let sections = match table.get("section") {
+            Some(sections) => sections.as_array().ok_or("section should be [[section]]")?,
+            None => &empty,
+        };
+        for section in sections {
+            let section = section.as_table().ok_or("section should be [[section]]")?;
+            let name = get_string(section, "name", "Section")?;
+            let load = get_string(section, "load", &format!("Section {}", name))?;
+            let run = match section.get("run") {
+                Some(_) => Some(get_string(section, "run", &format!("Section {}", name))?),
+                None => None,
+            };
+            for memory in [Some(&load), run.as_ref()].into_iter().flatten() {
+                if !config.memories.iter().any(|m| &m.name == memory) {
+                    return Err(format!("Unknow memory {} in section {}", memory, name));
+                }
+            }
+            if config.sections.iter().any(|s| s.name == name) {
+                return Err(format!("Section {} is placed twice", name));
+            }
+            config.sections.push(SectionPlacement { name, load, run });
+        }
+
+        Ok(config)
+    }
+
+    /// Give an address to every section. Without a linker script sections follow
+    /// `absolute_end`, in the order they are first used.
+    pub fn place_sections(
+        &self,
+        sections: &[SectionLayout],
+        absolute_end: u16,
+    ) -> Result<HashMap<String, Placement>, Vec<String>> {
+        let mut placements = HashMap::new();
+        let mut errors = vec![];
+
+        if self.sections.is_empty() && self.memories.is_empty() {
+            let mut cursor = absolute_end as u32;
+            for section in sections {
+                let start = align_up(cursor, section.alignment);
+                cursor = start + section.size as u32;
+                if cursor > 0x10000 {
+                    errors.push(format!(
+                        "Section {} does not fit in the address space",
+                        section.name
+                    ));
+                    continue;
+                }
+                placements.insert(
+                    section.name.to_string(),
+                    Placement {
+                        load: start as u16,
+                        run: start as u16,
+                    },
+                );
+            }
+            return if errors.is_empty() {
+                Ok(placements)
+            } else {
+                Err(errors)
+            };
+        }
+
+        for section in sections {
+            if !self.sections.iter().any(|s| s.name == section.name) {
+                errors.push(format!(
+                    "Section {} is not placed by the linker script",
+                    section.name
+                ));
+            }
+        }
+
+        let mut cursors = self
+            .memories
+            .iter()
+            .map(|m| (m.name.as_str(), m.start as u32))
+            .collect::<HashMap<_, _>>();
+        for placement in self.sections.iter() {
+            let (size, alignment) = sections
+                .iter()
+                .find(|s| s.name == placement.name)
+                .map(|s| (s.size, s.alignment))
+                .unwrap_or((0, 1));
+
+            let mut allocate = |memory_name: &str| {
+                let memory = self.memories.iter().find(|m| m.name == memory_name)?;
+                let cursor = cursors.get_mut(memory_name)?;
+                let start = align_up(*cursor, alignment);
+                *cursor = start + size as u32;
+                if *cursor > memory.start as u32 + memory.size {
+                    errors.push(format!(
+                        "Section {} does not fit in memory {}",
+                        placement.name, memory_name
+                    ));
+                }
+                Some(start as u16)
+            };
+            let Some(load) = allocate(&placement.load) else {
+                continue;
+            };
+            let run = match &placement.run {
+                Some(run) if *run != placement.load => allocate(run).unwrap_or(load),
+                _ => load,
+            };
+            placements.insert(placement.name.to_string(), Placement { load, run });
+        }
+
+        if errors.is_empty() {
+            Ok(placements)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = r#"
+[[memory]]
+name = "ram"
+start = 0x0000
+size = 0x100
+
+[[memory]]
+name = "rom"
+start = 0x8000
+size = 0x8000
+
+[[section]]
+name = "code"
+load = "rom"
+
+[[section]]
+name = "data"
+load = "rom"
+run = "ram"
+
+[[section]]
+name = "bss"
+load = "ram"
+"#;
+
+    fn layout(name: &str, size: u16, alignment: u16) -> SectionLayout {
+        SectionLayout {
+            name: name.to_string(),
+            size,
+            alignment,
+        }
+    }
+
+    #[test]
+    fn test_linker_config() {
+        let config = LinkerConfig::from_toml(SCRIPT).unwrap();
+        assert_eq!(config.memories.len(), 2);
+        assert_eq!(config.sections[1].run, Some("ram".to_string()));
+
+        assert!(LinkerConfig::from_toml("[[section]]\nname = \"code\"\nload = \"rom\"").is_err());
+        assert!(LinkerConfig::from_toml(
+            "[[memory]]\nname = \"rom\"\nstart = 0xFF00\nsize = 0x200"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_place_sections() {
+        let config = LinkerConfig::from_toml(SCRIPT).unwrap();
+        let placements = config
+            .place_sections(
+                &[
+                    layout("code", 5, 1),
+                    layout("data", 3, 1),
+                    layout("bss", 4, 4),
+                ],
+                0,
+            )
+            .unwrap();
+        assert_eq!(
+            placements["code"],
+            Placement {
+                load: 0x8000,
+                run: 0x8000
+            }
+        );
+        assert_eq!(
+            placements["data"],
+            Placement {
+                load: 0x8005,
+                run: 0
+            }
+        );
+        assert_eq!(placements["bss"], Placement { load: 4, run: 4 });
+
+        assert!(config
+            .place_sections(&[layout("bss", 0x101, 1)], 0)
+            .is_err());
+        assert!(config.place_sections(&[layout("other", 1, 1)], 0).is_err());
+
+        let placements = LinkerConfig::default()
+            .place_sections(&[layout("code", 5, 1), layout("table", 2, 8)], 3)
+            .unwrap();
+        assert_eq!(placements["code"], Placement { load: 3, run: 3 });
+        assert_eq!(placements["table"], Placement { load: 8, run: 8 });
+    }
+}
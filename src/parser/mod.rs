@@ -1,139 +1,797 @@
-use std::collections::HashMap;
-use std::fs::{read_to_string, exists};
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
-mod lexer;
+pub mod lexer;
+pub mod archive;
+pub mod linker;
+pub mod object;
+pub mod source;
 use lexer::*;
+use linker::{is_linker_symbol, LinkerConfig, SectionLayout};
+use object::{Object, PageAssertion, Relocation, RelocationTarget};
+use source::SourceProvider;
+use crate::cache::LexCache;
+use crate::diagnostic::Diagnostic;
+
+/// Address relative to the start of a section, or absolute outside of sections.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Location {
+    pub section: Option<String>,
+    pub addr: u16,
+}
+
+impl Location {
+    fn new(section: &Option<String>, addr: u16) -> Self {
+        Self { section: section.clone(), addr }
+    }
+
+    /// Absolute address once the sections start at `section_starts`.
+    pub fn placed(&self, section_starts: &HashMap<String, u16>) -> u16 {
+        match &self.section {
+            Some(section) => self.addr.wrapping_add(section_starts.get(section).copied().unwrap_or(0)),
+            None => self.addr,
+        }
+    }
+}
+
+/// Label, flag or instruction of a parsed file, with the address it is placed at.
+#[derive(Clone)]
+pub struct Statement {
+    /// Path of the file as it was found
+    pub file: String,
+    pub line: usize,
+    /// Tokens the statement was lexed from, with their byte range in the line
+    pub tokens: Vec<Token>,
+    /// Instructions have their local label reference qualified and their characters encoded
+    pub token_type: TokenType,
+    pub location: Location,
+    /// Scopes the statement is in, the namespace of the include first
+    pub scopes: Vec<String>,
+    /// Global label the local labels of the statement belong to
+    pub scope: Option<String>,
+}
+
+impl Statement {
+    /// Full name of a label defined by the statement.
+    pub fn label_name(&self, name: &str) -> Result<String, String> {
+        if is_local_reference(name) {
+            qualify_label(self.scope.as_deref(), name, &self.tokens)
+        } else {
+            Ok(scoped_name(&self.scopes, name))
+        }
+    }
+
+    /// Names a label referenced by the statement can resolve to, innermost scope first.
+    pub fn candidates(&self, name: &str) -> Vec<String> {
+        match qualify_label(self.scope.as_deref(), name, &self.tokens) {
+            Ok(name) => scoped_candidates(&self.scopes, &name),
+            Err(_) => vec![],
+        }
+    }
+}
+
+/// Error reported on a statement, or on a whole line that cannot be lexed.
+#[derive(Debug, PartialEq, Clone)]
+pub struct StatementError {
+    pub file: String,
+    pub line: usize,
+    /// Byte range of the statement in the line
+    pub span: Range<usize>,
+    pub message: String,
+}
+
+fn span_of(tokens: &[Token]) -> Range<usize> {
+    match (tokens.first(), tokens.last()) {
+        (Some(first), Some(last)) => first.span.start..last.span.end,
+        _ => 0..0,
+    }
+}
 
 pub struct IntermediateRepresentation {
-    labels: HashMap<String, u16>,
-    instructions: HashMap<u16, Instruction>,
+    labels: HashMap<String, Location>,
+    label_sites: HashMap<String, LabelSite>,
+    instructions: HashMap<Location, Instruction>,
+    /// Bytes of `.incbin` files and `.align` padding by address
+    data: HashMap<Location, Vec<u8>>,
+    /// Section of the label targeted by an instruction, its start is added when linking
+    relocations: HashMap<Location, String>,
+    page_assertions: Vec<PageAssertionSite>,
+    /// Labels exported with `.global`
+    globals: Vec<String>,
+    /// Labels declared with `.extern`
+    externs: HashSet<String>,
+    /// Sections of every parsed file, only set on the root file
+    sections: Vec<SectionLayout>,
+    /// Address following the last absolute byte, only set on the root file
+    absolute_end: u16,
+    /// Every file read while parsing, only set on the root file
+    dependencies: Vec<PathBuf>,
+    /// Statements of every parsed file, included files where they are included
+    statements: Vec<Statement>,
+    /// Errors of every parsed file, only set on the root file
+    errors: Vec<StatementError>,
 }
 
-impl IntermediateRepresentation {
-    pub fn new(str: &str) -> Option<Self> {
-        IntermediateRepresentation::parse(str, 0)
+/// Environment variable listing the standard library directories, searched after `-I`.
+pub const STDLIB_PATH_VAR: &str = "TASM_STDLIB_PATH";
+
+/// Directories searched for included files: the `-I` directories then the standard library ones.
+pub fn include_search_dirs(include_dirs: &[String]) -> Vec<PathBuf> {
+    let mut search_dirs = include_dirs.iter().map(PathBuf::from).collect::<Vec<_>>();
+    if let Some(stdlib_path) = env::var_os(STDLIB_PATH_VAR) {
+        search_dirs.extend(env::split_paths(&stdlib_path));
     }
+    search_dirs
+}
 
-    fn parse(str: &str, offset_addr: u16) -> Option<Self> {
-        let file_path = exists(str);
-        if let Ok(exist) = file_path {
-            if !exist {
-                eprintln!("ERR: Path does not exist: {}", str);
-                return None;
+/// Find an included file relative to the including file, then in the search directories.
+/// Return the relative path if the file is found nowhere.
+pub fn resolve_include(path_str: &str, parent_dir: &Path, search_dirs: &[PathBuf], sources: &dyn SourceProvider) -> PathBuf {
+    let relative_path = parent_dir.join(path_str);
+    if sources.exists(&relative_path) {
+        return relative_path;
+    }
+    search_dirs.iter()
+        .map(|dir| dir.join(path_str))
+        .find(|path| sources.exists(path))
+        .unwrap_or(relative_path)
+}
+
+/// Read the bytes of a file included with `.incbin`.
+pub fn read_binary(path: &Path, offset: usize, length: Option<usize>, sources: &dyn SourceProvider) -> Result<Vec<u8>, String> {
+    let bytes = sources.read(path)?;
+    let end = match length {
+        Some(length) => offset.checked_add(length),
+        None => Some(bytes.len()),
+    };
+    match end {
+        Some(end) if offset <= end && end <= bytes.len() => {
+            if end - offset > u16::MAX as usize {
+                Err(format!("File {} does not fit in memory", path.display()))
+            } else {
+                Ok(bytes[offset..end].to_vec())
             }
-        } else {
-            eprintln!("ERR: Path cannot be determine: {}", str);
-            return None;
+        },
+        _ => Err(format!("Range is outside of the {} bytes of {}", bytes.len(), path.display())),
+    }
+}
+
+/// State shared by a file and all the files it includes.
+struct IncludeContext<'a> {
+    sources: &'a dyn SourceProvider,
+    /// Lexed lines of the files by contents, kept between assemblies by the caller
+    cache: &'a LexCache,
+    /// Symbols set before the first line of the root file
+    defines: Vec<(String, u16)>,
+    search_dirs: Vec<PathBuf>,
+    /// Files being parsed, from the root file to the current one
+    stack: Vec<(PathBuf, String)>,
+    /// Every file parsed so far, for `.include_once`
+    parsed: HashSet<PathBuf>,
+    /// Current address in each section, `None` for absolute addresses
+    positions: HashMap<Option<String>, u32>,
+    /// Sections in the order they are first used
+    sections: Vec<String>,
+    /// Alignment required by the `.align` flags of each section
+    alignments: HashMap<String, u16>,
+    /// Every file read, in the order they are first read
+    dependencies: Vec<PathBuf>,
+    /// Codes of the characters set by `.charmap`, the others are encoded as themselves
+    charmap: HashMap<char, u8>,
+    diagnostics: Vec<Diagnostic>,
+    errors: Vec<StatementError>,
+}
+
+impl<'a> IncludeContext<'a> {
+    fn new(include_dirs: &[String], defines: &[(String, u16)], sources: &'a dyn SourceProvider, cache: &'a LexCache) -> Self {
+        Self {
+            sources,
+            cache,
+            defines: defines.to_vec(),
+            search_dirs: include_search_dirs(include_dirs),
+            stack: Vec::new(),
+            parsed: HashSet::new(),
+            positions: HashMap::new(),
+            sections: Vec::new(),
+            alignments: HashMap::new(),
+            dependencies: Vec::new(),
+            charmap: HashMap::new(),
+            diagnostics: Vec::new(),
+            errors: Vec::new(),
         }
+    }
+
+    /// Report an error on the statement at `span` of `line` in the file being parsed.
+    fn error(&mut self, message: String, line: usize, span: Range<usize>) {
+        let file = self.stack.last().map(|(_, path_str)| path_str.to_string()).unwrap_or_default();
+        self.error_in(file, message, line, span);
+    }
+
+    /// Report an error on a statement of `file`, e.g. a label of an included file.
+    fn error_in(&mut self, file: String, message: String, line: usize, span: Range<usize>) {
+        self.diagnostics.push(Diagnostic::error(message.to_string(), Some(line)));
+        self.errors.push(StatementError { file, line, span, message });
+    }
+}
+
+fn add_dependency(dependencies: &mut Vec<PathBuf>, path: &Path) {
+    if !dependencies.iter().any(|dependency| dependency == path) {
+        dependencies.push(path.to_path_buf());
+    }
+}
+
+/// Code of a character in the character map, or its own code when it is not mapped.
+fn encode_char(charmap: &HashMap<char, u8>, c: char) -> Result<u8, String> {
+    charmap.get(&c).copied()
+        .or_else(|| u8::try_from(c as u32).ok())
+        .ok_or(format!("Character {:?} is not in the character map and does not fit in 8 bits", c))
+}
+
+/// Address following `size` bytes placed at `addr`. Absolute code can fill the memory up to
+/// $FFFF, the size of a section has to fit in 16 bits.
+fn advance(section: &Option<String>, addr: u32, size: usize) -> Result<u32, String> {
+    let end = addr as usize + size;
+    match section {
+        None if end > 0x10000 => Err(format!("Address {:#06x} + {} goes past $FFFF", addr, size)),
+        Some(name) if end > 0xFFFF => Err(format!("Section {} goes past $FFFF bytes", name)),
+        _ => Ok(end as u32),
+    }
+}
+
+/// File of the defines in the messages about labels.
+const DEFINE_FILE: &str = "<define>";
+
+/// `.assert_page` checked at the end of the file: label, scopes, section, end of the block
+/// up to $10000, line and span.
+type PendingPageAssertion = (String, Vec<String>, Option<String>, u32, usize, Range<usize>);
+
+/// `.assert_page` block with the file and span of the statement it is reported on.
+#[derive(Clone)]
+struct PageAssertionSite {
+    page_assertion: PageAssertion,
+    file: String,
+    span: Range<usize>,
+}
+
+/// Where a label is defined, `rebindable` for symbols defined with `.set`.
+#[derive(Clone)]
+struct LabelSite {
+    file: String,
+    line: usize,
+    span: Range<usize>,
+    rebindable: bool,
+}
+
+/// Insert a label, reporting it on its site if it is already defined, with the previous site
+/// in the message. Only `.set` symbols can be defined again by another `.set`.
+fn define_label(labels: &mut HashMap<String, Location>, label_sites: &mut HashMap<String, LabelSite>, label_name: String, location: Location, site: LabelSite, context: &mut IncludeContext) {
+    if let Some(previous_site) = label_sites.get(&label_name) {
+        if !(previous_site.rebindable && site.rebindable) {
+            let message = format!("Label {} is already defined at line {} in {}", label_name, previous_site.line, previous_site.file);
+            context.error_in(site.file.to_string(), message, site.line, site.span.clone());
+        }
+    }
+    labels.insert(label_name.to_string(), location);
+    label_sites.insert(label_name, site);
+}
+
+impl IntermediateRepresentation {
+    /// Parse a file read from `sources`, included files are also searched in `include_dirs`.
+    /// `defines` are symbols set before the first line, like `.set`. Files already in `cache`
+    /// are not lexed again.
+    pub fn new(str: &str, include_dirs: &[String], defines: &[(String, u16)], sources: &dyn SourceProvider, cache: &LexCache, diagnostics: &mut Vec<Diagnostic>) -> Option<Self> {
+        let mut context = IncludeContext::new(include_dirs, defines, sources, cache);
+        let source_code = match sources.read_to_string(Path::new(str)) {
+            Ok(source_code) => source_code,
+            Err(err_str) => {
+                diagnostics.push(Diagnostic::error(err_str, None));
+                return None;
+            },
+        };
+        let representation = IntermediateRepresentation::parse(str, &source_code, None, &mut context);
+        representation.check_references(&mut context);
+        diagnostics.append(&mut context.diagnostics);
+        Some(representation.with_layout(&mut context))
+    }
+
+    /// Set the sections and the end of the absolute code once the root file is parsed.
+    fn with_layout(self, context: &mut IncludeContext) -> Self {
+        let mut representation = self;
+        representation.sections = context.sections.iter()
+            .map(|name| SectionLayout {
+                name: name.to_string(),
+                size: context.positions.get(&Some(name.to_string())).copied().unwrap_or(0) as u16,
+                alignment: context.alignments.get(name).copied().unwrap_or(1),
+            })
+            .collect();
+        representation.absolute_end = representation.instructions.iter()
+            .map(|(location, instruction)| (location, instruction.size as u32))
+            .chain(representation.data.iter().map(|(location, bytes)| (location, bytes.len() as u32)))
+            .filter(|(location, _)| location.section.is_none())
+            .map(|(location, size)| location.addr as u32 + size)
+            .chain(context.positions.get(&None).copied())
+            .max()
+            .unwrap_or(0)
+            .min(u16::MAX as u32) as u16;
+        representation.dependencies = context.dependencies.clone();
+        representation.errors = std::mem::take(&mut context.errors);
+        representation
+    }
+
+    /// Files the program is made of, the root file first, as they were found.
+    pub fn dependencies(&self) -> &[PathBuf] {
+        &self.dependencies
+    }
+
+    /// Statements of the program in the order they are parsed, for the language server and the linter.
+    pub fn statements(&self) -> &[Statement] {
+        &self.statements
+    }
+
+    /// Errors located on the statements of the program, also reported as diagnostics.
+    pub fn errors(&self) -> &[StatementError] {
+        &self.errors
+    }
+
+    pub fn label(&self, name: &str) -> Option<&Location> {
+        self.labels.get(name)
+    }
+
+    /// Labels exported with `.global`, by full name.
+    pub fn globals(&self) -> &[String] {
+        &self.globals
+    }
 
-        let source_code = read_to_string(str).unwrap();
+    /// Location of the label targeted by the instruction at `location`, if resolved in the program.
+    pub fn target(&self, location: &Location) -> Option<Location> {
+        match self.instructions.get(location)?.linked_data {
+            Some(InstructionLinkedData::Relative(addr)) => Some(Location { section: self.relocations.get(location).cloned(), addr }),
+            _ => None,
+        }
+    }
+
+    /// Start of each section when the program is linked alone without a linker script.
+    pub fn section_starts(&self) -> Result<HashMap<String, u16>, Vec<String>> {
+        let placements = LinkerConfig::default().place_sections(&self.sections, self.absolute_end)?;
+        Ok(placements.into_iter().map(|(name, placement)| (name, placement.run)).collect())
+    }
+
+    fn parse(str: &str, source_code: &str, section: Option<String>, context: &mut IncludeContext) -> Self {
+        add_dependency(&mut context.dependencies, Path::new(str));
+        let canonical_path = context.sources.canonicalize(Path::new(str));
+        context.parsed.insert(canonical_path.clone());
+        context.stack.push((canonical_path, str.to_string()));
         let parent_dir_path = {
             let mut path = PathBuf::from(str);
             path.pop();
             path
         };
 
-        let mut labels: HashMap<String, u16> = HashMap::new();
-        let mut instructions: HashMap<u16, Instruction> = HashMap::new();
-
-        let mut current_addr: u16 = offset_addr;
-        for (line_index, line_raw) in source_code.lines().enumerate() {
-            match lex_line(line_raw) {
-                Ok(token_opt) => {
-                    if let Some(token) = token_opt {
-                        match token {
-                            TokenType::Instruction(instruction) => {
-                                let incr_addr = instruction.size;
-                                instructions.insert(current_addr, instruction);
-                                current_addr += incr_addr;
+        let mut labels: HashMap<String, Location> = HashMap::new();
+        let mut label_sites: HashMap<String, LabelSite> = HashMap::new();
+        let mut set_values: HashMap<String, u16> = HashMap::new();
+        let mut instructions: HashMap<Location, Instruction> = HashMap::new();
+        let mut data: HashMap<Location, Vec<u8>> = HashMap::new();
+        let mut relocations: HashMap<Location, String> = HashMap::new();
+        let mut page_assertions: Vec<PageAssertionSite> = Vec::new();
+        let mut statements: Vec<Statement> = Vec::new();
+
+        // Included files start in the section of the including file
+        let mut section = section;
+        // Absolute code can end at $10000, the address following the last byte
+        let mut current_addr: u32 = context.positions.get(&section).copied().unwrap_or(0);
+        let mut scope: Option<String> = None;
+        let mut scopes: Vec<String> = Vec::new();
+        let mut reference_scopes: HashMap<Location, Vec<String>> = HashMap::new();
+        let mut anonymous_labels: Vec<Location> = Vec::new();
+        let mut anonymous_references: Vec<(Location, usize, usize, Range<usize>)> = Vec::new();
+        let mut pending_page_assertions: Vec<PendingPageAssertion> = Vec::new();
+        let mut pending_globals: Vec<(String, Vec<String>, usize, Range<usize>)> = Vec::new();
+        let mut globals: Vec<String> = Vec::new();
+        let mut externs: HashSet<String> = HashSet::new();
+        if context.stack.len() == 1 {
+            for (label_name, value) in context.defines.clone().iter() {
+                set_values.insert(label_name.to_string(), *value);
+                let site = LabelSite { file: DEFINE_FILE.to_string(), line: 0, span: 0..0, rebindable: true };
+                define_label(&mut labels, &mut label_sites, label_name.to_string(), Location::new(&None, *value), site, context);
+            }
+        }
+        let lexed_lines = context.cache.lex(source_code);
+        for (line_index, lexed_line) in lexed_lines.iter().enumerate() {
+            let site = |span: &Range<usize>, rebindable| LabelSite { file: str.to_string(), line: line_index, span: span.clone(), rebindable };
+            match lexed_line.clone() {
+                Ok(lexed_tokens) => {
+                    for LexedToken { token_type, tokens } in lexed_tokens {
+                        let span = span_of(&tokens);
+                        statements.push(Statement {
+                            file: str.to_string(),
+                            line: line_index,
+                            tokens: tokens.clone(),
+                            token_type: token_type.clone(),
+                            location: Location::new(&section, current_addr as u16),
+                            scopes: scopes.clone(),
+                            scope: scope.clone(),
+                        });
+                        match token_type {
+                            TokenType::Instruction(mut instruction) => {
+                                let mut set_value = None;
+                                if let Some(InstructionLinkedData::NotResolvedRelative(label)) = &instruction.linked_data {
+                                    if is_anonymous_reference(label) {
+                                        anonymous_references.push((Location::new(&section, current_addr as u16), anonymous_labels.len(), line_index, span.clone()));
+                                    } else if !is_local_reference(label) {
+                                        // `.set` symbols use their value at the point of reference
+                                        set_value = scoped_candidates(&scopes, label).iter().find_map(|label| set_values.get(label)).copied();
+                                        reference_scopes.insert(Location::new(&section, current_addr as u16), scopes.clone());
+                                    } else {
+                                        match qualify_label(scope.as_deref(), label, &tokens) {
+                                            Ok(label) => instruction.linked_data = Some(InstructionLinkedData::NotResolvedRelative(label)),
+                                            Err(err_str) => context.error(err_str, line_index, span.clone()),
+                                        }
+                                    }
+                                }
+                                if let Some(InstructionLinkedData::Characters(tokens)) = &instruction.linked_data {
+                                    match parse_constant_with::<u8>(tokens, &|c| encode_char(&context.charmap, c).map(i64::from)) {
+                                        Ok(code) => instruction.linked_data = Some(InstructionLinkedData::Immediate(code)),
+                                        Err(err_str) => context.error(err_str, line_index, span.clone()),
+                                    }
+                                }
+                                // The statement keeps the name of the label referenced
+                                if let Some(statement) = statements.last_mut() {
+                                    statement.token_type = TokenType::Instruction(instruction.clone());
+                                }
+                                if let Some(value) = set_value {
+                                    instruction.linked_data = Some(InstructionLinkedData::Relative(value));
+                                }
+                                match advance(&section, current_addr, instruction.size as usize) {
+                                    Ok(next_addr) => {
+                                        instructions.insert(Location::new(&section, current_addr as u16), instruction);
+                                        current_addr = next_addr;
+                                    },
+                                    Err(err_str) => context.error(err_str, line_index, span.clone()),
+                                }
                             },
                             TokenType::Flag(flag) => {
                                 match flag {
                                     Flag::Org(addr) => {
-                                        current_addr = addr;
+                                        context.positions.insert(section.clone(), current_addr);
+                                        section = None;
+                                        current_addr = addr as u32;
+                                    },
+                                    Flag::Section(section_name) => {
+                                        context.positions.insert(section.clone(), current_addr);
+                                        if !context.sections.contains(&section_name) {
+                                            context.sections.push(section_name.to_string());
+                                        }
+                                        section = Some(section_name);
+                                        current_addr = context.positions.get(&section).copied().unwrap_or(0);
                                     },
-                                    Flag::Include(path_str) => {
-                                        let mut include_full_path = parent_dir_path.clone();
-                                        include_full_path.push(path_str);
+                                    Flag::Include(include) => {
+                                        let include_full_path = resolve_include(&include.path, &parent_dir_path, &context.search_dirs, context.sources);
                                         let path_str = include_full_path.to_str().unwrap();
-                                        println!("INFO: Compiling file {}", path_str);
-                                        let nested_representation_opt = IntermediateRepresentation::parse(path_str, current_addr);
-                                        if let Some(nested_representation) = nested_representation_opt {
-                                            current_addr += nested_representation.bytes_size();
-                                            labels.extend(nested_representation.labels);
-                                            instructions.extend(nested_representation.instructions);
+                                        let canonical_path = context.sources.canonicalize(&include_full_path);
+                                        if let Some(position) = context.stack.iter().position(|(path, _)| *path == canonical_path) {
+                                            let cycle = context.stack[position..].iter()
+                                                .map(|(_, path_str)| path_str.as_str())
+                                                .chain([path_str])
+                                                .collect::<Vec<_>>();
+                                            context.error(format!("Include cycle: {}", cycle.join(" -> ")), line_index, span);
+                                            continue;
+                                        }
+                                        if include.once && context.parsed.contains(&canonical_path) {
+                                            context.diagnostics.push(Diagnostic::info(format!("Skipping file {} already included", path_str)));
+                                            continue;
+                                        }
+                                        let include_source_code = match context.sources.read_to_string(&include_full_path) {
+                                            Ok(include_source_code) => include_source_code,
+                                            Err(err_str) => {
+                                                context.error(err_str, line_index, span);
+                                                continue;
+                                            },
+                                        };
+                                        context.diagnostics.push(Diagnostic::info(format!("Compiling file {}", path_str)));
+                                        context.positions.insert(section.clone(), current_addr);
+                                        let nested_representation = IntermediateRepresentation::parse(path_str, &include_source_code, section.clone(), context);
+                                        current_addr = context.positions.get(&section).copied().unwrap_or(current_addr);
+                                        let prefix = scopes.iter().cloned().chain(include.namespace).collect::<Vec<_>>();
+                                        for (label_name, location) in nested_representation.labels {
+                                            let nested_site = nested_representation.label_sites[&label_name].clone();
+                                            let label_name = scoped_name(&prefix, &label_name);
+                                            if nested_site.rebindable {
+                                                set_values.insert(label_name.to_string(), location.addr);
+                                            }
+                                            define_label(&mut labels, &mut label_sites, label_name, location, nested_site, context);
+                                        }
+                                        instructions.extend(nested_representation.instructions);
+                                        data.extend(nested_representation.data);
+                                        relocations.extend(nested_representation.relocations);
+                                        page_assertions.extend(nested_representation.page_assertions);
+                                        globals.extend(nested_representation.globals.iter().map(|label_name| scoped_name(&prefix, label_name)));
+                                        externs.extend(nested_representation.externs);
+                                        statements.extend(nested_representation.statements.into_iter().map(|mut statement| {
+                                            statement.scopes = prefix.iter().cloned().chain(statement.scopes).collect();
+                                            statement.scope = statement.scope.map(|scope| scoped_name(&prefix, &scope));
+                                            statement
+                                        }));
+                                    },
+                                    Flag::IncludeBinary(include_binary) => {
+                                        let binary_path = resolve_include(&include_binary.path, &parent_dir_path, &context.search_dirs, context.sources);
+                                        match read_binary(&binary_path, include_binary.offset, include_binary.length, context.sources) {
+                                            Ok(bytes) => {
+                                                add_dependency(&mut context.dependencies, &binary_path);
+                                                context.diagnostics.push(Diagnostic::info(format!("Including {} bytes of {} at current_addr {:#06x}", bytes.len(), binary_path.display(), current_addr)));
+                                                match advance(&section, current_addr, bytes.len()) {
+                                                    Ok(next_addr) => {
+                                                        data.insert(Location::new(&section, current_addr as u16), bytes);
+                                                        current_addr = next_addr;
+                                                    },
+                                                    Err(err_str) => context.error(err_str, line_index, span.clone()),
+                                                }
+                                            },
+                                            Err(err_str) => context.error(err_str, line_index, span.clone()),
+                                        }
+                                    },
+                                    Flag::Align((alignment, fill)) => {
+                                        if let Some(section_name) = &section {
+                                            // The start of the section is aligned when it is placed
+                                            let section_alignment = context.alignments.entry(section_name.to_string()).or_insert(1);
+                                            *section_alignment = num::integer::lcm(*section_alignment, alignment);
+                                        }
+                                        let padding = (alignment as u32 - current_addr % alignment as u32) % alignment as u32;
+                                        match advance(&section, current_addr, padding as usize) {
+                                            Ok(next_addr) => {
+                                                if let Some(fill) = fill.filter(|_| padding > 0) {
+                                                    data.insert(Location::new(&section, current_addr as u16), vec![fill; padding as usize]);
+                                                }
+                                                current_addr = next_addr;
+                                            },
+                                            Err(err_str) => context.error(err_str, line_index, span.clone()),
+                                        }
+                                    },
+                                    Flag::Reserve(size) => {
+                                        match advance(&section, current_addr, size as usize) {
+                                            Ok(next_addr) => current_addr = next_addr,
+                                            Err(err_str) => context.error(err_str, line_index, span.clone()),
+                                        }
+                                    },
+                                    Flag::Ascii(str) => {
+                                        match str.chars().map(|c| encode_char(&context.charmap, c)).collect::<Result<Vec<u8>, String>>() {
+                                            Ok(bytes) if bytes.is_empty() => {},
+                                            Ok(bytes) => {
+                                                match advance(&section, current_addr, bytes.len()) {
+                                                    Ok(next_addr) => {
+                                                        data.insert(Location::new(&section, current_addr as u16), bytes);
+                                                        current_addr = next_addr;
+                                                    },
+                                                    Err(err_str) => context.error(err_str, line_index, span.clone()),
+                                                }
+                                            },
+                                            Err(err_str) => context.error(err_str, line_index, span.clone()),
+                                        }
+                                    },
+                                    Flag::Charmap(charmap) => {
+                                        for (offset, c) in (charmap.first..=charmap.last).enumerate() {
+                                            context.charmap.insert(c, charmap.code + offset as u8);
+                                        }
+                                    },
+                                    Flag::AssertPage(label_name) => {
+                                        match qualify_label(scope.as_deref(), &label_name, &tokens) {
+                                            Ok(label_name) => pending_page_assertions.push((label_name, scopes.clone(), section.clone(), current_addr, line_index, span)),
+                                            Err(err_str) => context.error(err_str, line_index, span.clone()),
                                         }
                                     },
                                     Flag::Label((label_name, addr)) => {
-                                        labels.insert(label_name.to_string(), addr);
-                                        println!("INFO: label {} at current_addr {:#06x}", label_name, addr);
-                                    }
+                                        let label_name = scoped_name(&scopes, &label_name);
+                                        context.diagnostics.push(Diagnostic::info(format!("label {} at current_addr {:#06x}", label_name, addr)));
+                                        define_label(&mut labels, &mut label_sites, label_name, Location::new(&None, addr), site(&span, false), context);
+                                    },
+                                    Flag::Set((label_name, value)) => {
+                                        let label_name = scoped_name(&scopes, &label_name);
+                                        context.diagnostics.push(Diagnostic::info(format!("set {} to {:#06x}", label_name, value)));
+                                        set_values.insert(label_name.to_string(), value);
+                                        define_label(&mut labels, &mut label_sites, label_name, Location::new(&None, value), site(&span, true), context);
+                                    },
+                                    Flag::Global(label_name) => {
+                                        match qualify_label(scope.as_deref(), &label_name, &tokens) {
+                                            Ok(label_name) => pending_globals.push((label_name, scopes.clone(), line_index, span)),
+                                            Err(err_str) => context.error(err_str, line_index, span.clone()),
+                                        }
+                                    },
+                                    Flag::Extern(label_name) => {
+                                        externs.insert(label_name);
+                                    },
+                                    Flag::Scope(scope_name) => {
+                                        scopes.push(scope_name);
+                                    },
+                                    Flag::EndScope => {
+                                        if scopes.pop().is_none() {
+                                            context.error(".endscope without matching .scope".to_string(), line_index, span);
+                                        }
+                                    },
                                 }
                             },
                             TokenType::Label(label) => {
-                                labels.insert(label.name.to_string(), current_addr);
-                                println!("INFO: label {} at current_addr {:#06x}", label.name, current_addr);
+                                if label.is_anonymous() {
+                                    anonymous_labels.push(Location::new(&section, current_addr as u16));
+                                    continue;
+                                }
+                                if !label.is_local() {
+                                    scope = Some(scoped_name(&scopes, &label.name));
+                                }
+                                let label_name = if label.is_local() {
+                                    qualify_label(scope.as_deref(), &label.name, &tokens)
+                                } else {
+                                    Ok(scoped_name(&scopes, &label.name))
+                                };
+                                let label_name = label_name.and_then(|label_name| match current_addr {
+                                    0..=0xFFFF => Ok(label_name),
+                                    _ => Err(format!("Label {} is past $FFFF", label_name)),
+                                });
+                                match label_name {
+                                    Ok(label_name) => {
+                                        context.diagnostics.push(Diagnostic::info(format!("label {} at current_addr {:#06x}", label_name, current_addr)));
+                                        define_label(&mut labels, &mut label_sites, label_name, Location::new(&section, current_addr as u16), site(&span, false), context);
+                                    },
+                                    Err(err_str) => context.error(err_str, line_index, span.clone()),
+                                }
                             },
                         }
-
-                    } else {
-                        continue;
                     }
                 },
-                Err(err_str) => {
-                    eprintln!("ERR: {} | at line {}", err_str, line_index);
+                Err(error) => context.error(error.message, line_index, error.span),
+            }
+        }
+
+        if let Some(scope_name) = scopes.last() {
+            context.diagnostics.push(Diagnostic::error(format!("Scope {} is not closed at the end of {}", scope_name, str), None));
+        }
+
+        for (label_name, reference_scopes, end_section, end_addr, line_index, span) in pending_page_assertions {
+            match scoped_candidates(&reference_scopes, &label_name).iter().find_map(|label| labels.get(label)) {
+                Some(start) if start.section != end_section => {
+                    context.error(format!("Label {} should be in the same section as .assert_page", label_name), line_index, span);
+                },
+                Some(start) if start.addr as u32 >= end_addr => {
+                    context.error(format!("Label {} should be defined before .assert_page", label_name), line_index, span);
+                },
+                Some(start) => page_assertions.push(PageAssertionSite {
+                    page_assertion: PageAssertion {
+                        label_name,
+                        section: end_section,
+                        start_addr: start.addr,
+                        end_addr,
+                        line: line_index,
+                    },
+                    file: str.to_string(),
+                    span,
+                }),
+                None => context.error(format!("Label {} didn't exist!", label_name), line_index, span),
+            }
+        }
+
+        for (label_name, reference_scopes, line_index, span) in pending_globals {
+            match scoped_candidates(&reference_scopes, &label_name).into_iter().find(|label| labels.contains_key(label)) {
+                Some(label_name) => globals.push(label_name),
+                None => context.error(format!("Label {} didn't exist!", label_name), line_index, span),
+            }
+        }
+
+        for (location, defined_before, line_index, span) in anonymous_references {
+            // Instructions going past $FFFF are not placed
+            let Some(instruction) = instructions.get_mut(&location) else {
+                continue;
+            };
+            if let Some(InstructionLinkedData::NotResolvedRelative(reference)) = &instruction.linked_data {
+                match resolve_anonymous_label(reference, defined_before, &anonymous_labels) {
+                    Ok(label_location) => {
+                        instruction.linked_data = Some(InstructionLinkedData::Relative(label_location.addr));
+                        if let Some(label_section) = label_location.section {
+                            relocations.insert(location, label_section);
+                        }
+                    },
+                    Err(err_str) => context.error(err_str, line_index, span),
                 }
             }
         }
 
-        for instruction in instructions.values_mut() {
+        // Labels still missing are searched in the including files, then when linking
+        for (location, instruction) in instructions.iter_mut() {
             if let Some(InstructionLinkedData::NotResolvedRelative(label)) = &instruction.linked_data
             {
-                if let Some(label_addr) = labels.get(label) {
-                    instruction.linked_data = Some(InstructionLinkedData::Relative(*label_addr));
-                } else {
-                    eprintln!("ERR: Label {} didn't exist!", label);
+                if is_anonymous_reference(label) {
+                    continue;
+                }
+                let reference_scopes = reference_scopes.get(location).map(Vec::as_slice).unwrap_or_default();
+                if let Some(label_location) = scoped_candidates(reference_scopes, label).iter().find_map(|label| labels.get(label)) {
+                    instruction.linked_data = Some(InstructionLinkedData::Relative(label_location.addr));
+                    if let Some(label_section) = &label_location.section {
+                        relocations.insert(location.clone(), label_section.to_string());
+                    }
                 }
             }
         }
 
-        Some(Self {
+        context.positions.insert(section, current_addr);
+        context.stack.pop();
+        Self {
             labels,
+            label_sites,
             instructions,
-        })
+            data,
+            relocations,
+            page_assertions,
+            globals,
+            externs,
+            sections: Vec::new(),
+            absolute_end: 0,
+            dependencies: Vec::new(),
+            statements,
+            errors: Vec::new(),
+        }
     }
 
-    pub fn bytes_size(&self) -> u16 {
-        if self.instructions.is_empty() {
-            0
-        } else {
-            let min_instruction_address = self.instructions.keys().min().unwrap();
-            let max_instruction_address = self.instructions.keys().max().unwrap();
-
-            (max_instruction_address - min_instruction_address) + self.instructions[max_instruction_address].size
+    /// Report the labels still missing once the root file is parsed, unless they are declared
+    /// with `.extern` or defined by the linker.
+    fn check_references(&self, context: &mut IncludeContext) {
+        for statement in self.statements.iter() {
+            let TokenType::Instruction(Instruction { linked_data: Some(InstructionLinkedData::NotResolvedRelative(_)), .. }) = &statement.token_type else {
+                continue;
+            };
+            let Some(Instruction { linked_data: Some(InstructionLinkedData::NotResolvedRelative(label)), .. }) = self.instructions.get(&statement.location) else {
+                continue;
+            };
+            // Anonymous and local labels without a global label are reported while parsing
+            if is_anonymous_reference(label) || is_local_reference(label) || self.externs.contains(label) || is_linker_symbol(label) {
+                continue;
+            }
+            let operand = statement.tokens[1..].rsplit(|t| t.kind == TokenKind::Comma).next().unwrap_or_default();
+            context.error_in(statement.file.to_string(), format!("Label {} didn't exist!", label), statement.line, span_of(operand));
         }
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let size = self.bytes_size() as usize;
-        let addr_map = {
-            let mut vec = self.instructions.keys().collect::<Vec<_>>();
-            vec.sort();
-            vec
-        };
-        let mut memory = vec![0; size];
-
-        let offset_addr = addr_map[0];
-        for addr in addr_map {
-            let current_instruction = self.instructions.get(addr).unwrap();
-            let bytes_rep = current_instruction.to_bytes();
-            let bytes_addr_slice = (addr - offset_addr) as usize
-                ..((addr - offset_addr) as usize + bytes_rep.len());
+    /// Errors of the `.assert_page` blocks crossing a page once the sections start at `section_starts`.
+    pub fn page_errors(&self, section_starts: &HashMap<String, u16>) -> Vec<StatementError> {
+        self.page_assertions.iter()
+            .filter_map(|site| {
+                let page_assertion = &site.page_assertion;
+                let section_start = page_assertion.section.as_ref().and_then(|section| section_starts.get(section)).copied().unwrap_or(0) as u32;
+                let message = page_assertion.crossing_error(section_start + page_assertion.start_addr as u32, section_start + page_assertion.end_addr)?;
+                Some(StatementError { file: site.file.to_string(), line: page_assertion.line, span: site.span.clone(), message })
+            })
+            .collect()
+    }
 
-            memory[bytes_addr_slice].copy_from_slice(bytes_rep.as_slice());
+    /// Object exporting the `.global` labels. Labels that are still missing should be
+    /// declared with `.extern` or defined by the linker.
+    pub fn to_object(&self, name: &str) -> Object {
+        let mut chunks = Vec::new();
+        let mut relocations = Vec::new();
+        for (location, instruction) in self.instructions.iter() {
+            match &instruction.linked_data {
+                Some(InstructionLinkedData::Relative(_)) => {
+                    if let Some(label_section) = self.relocations.get(location) {
+                        relocations.push(Relocation { location: location.clone(), target: RelocationTarget::Section(label_section.to_string()) });
+                    }
+                },
+                // Other missing labels are reported by `check_references`
+                Some(InstructionLinkedData::NotResolvedRelative(label)) if self.externs.contains(label) || is_linker_symbol(label) => {
+                    relocations.push(Relocation { location: location.clone(), target: RelocationTarget::Symbol(label.to_string()) });
+                },
+                _ => {},
+            }
+            chunks.push((location.clone(), instruction.to_bytes()));
         }
+        chunks.extend(self.data.iter().map(|(location, bytes)| (location.clone(), bytes.clone())));
 
-        memory
+        Object {
+            name: name.to_string(),
+            sections: self.sections.clone(),
+            absolute_end: self.absolute_end,
+            chunks,
+            symbols: self.globals.iter().map(|label_name| (label_name.to_string(), self.labels[label_name].clone())).collect(),
+            relocations,
+            page_assertions: self.page_assertions.iter().map(|site| site.page_assertion.clone()).collect(),
+        }
     }
 }
@@ -0,0 +1,528 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+use super::linker::{LinkerConfig, SectionLayout};
+use super::Location;
+use crate::diagnostic::Diagnostic;
+
+const OBJECT_FORMAT: &str = "tasm-object-1";
+
+/// Address patched once sections are placed, the start of a section is added to the
+/// address already stored while a symbol replaces it.
+pub enum RelocationTarget {
+    Section(String),
+    Symbol(String),
+}
+
+/// Two address bytes following the opcode of the instruction at `location`.
+pub struct Relocation {
+    pub location: Location,
+    pub target: RelocationTarget,
+}
+
+/// Block checked by `.assert_page` once its section is placed.
+#[derive(Clone)]
+pub struct PageAssertion {
+    pub label_name: String,
+    pub section: Option<String>,
+    pub start_addr: u16,
+    /// Address following the block, up to $10000
+    pub end_addr: u32,
+    pub line: usize,
+}
+
+impl PageAssertion {
+    /// Error if the block from `start_addr` up to `end_addr` crosses a page boundary.
+    pub fn crossing_error(&self, start_addr: u32, end_addr: u32) -> Option<String> {
+        let last_addr = end_addr.saturating_sub(1).max(start_addr);
+        (start_addr >> 8 != last_addr >> 8).then(|| {
+            format!(
+                "Block from {} at {:#06x} to {:#06x} crosses a page boundary",
+                self.label_name, start_addr, last_addr
+            )
+        })
+    }
+}
+
+/// Assembled file whose sections are not placed yet, written by `tasm assemble -c`.
+pub struct Object {
+    pub name: String,
+    pub sections: Vec<SectionLayout>,
+    /// Address following the last absolute byte
+    pub absolute_end: u16,
+    pub chunks: Vec<(Location, Vec<u8>)>,
+    /// Labels exported with `.global`
+    pub symbols: Vec<(String, Location)>,
+    pub relocations: Vec<Relocation>,
+    pub page_assertions: Vec<PageAssertion>,
+}
+
+/// Bytes at their final address.
+pub struct Image {
+    /// Sorted by address
+    pub chunks: Vec<(u16, Vec<u8>)>,
+}
+
+fn location_to_json(location: &Location) -> Value {
+    json!({ "section": location.section, "addr": location.addr })
+}
+
+fn location_from_json(value: &Value) -> Result<Location, String> {
+    let section = match value.get("section") {
+        None | Some(Value::Null) => None,
+        Some(section) => Some(string_from_json(section)?),
+    };
+    Ok(Location {
+        section,
+        addr: number_from_json(value.get("addr"))?,
+    })
+}
+
+fn string_from_json(value: &Value) -> Result<String, String> {
+    value
+        .as_str()
+        .map(str::to_string)
+        .ok_or(format!("Expected a string: {}", value))
+}
+
+fn number_from_json<T: TryFrom<u64>>(value: Option<&Value>) -> Result<T, String> {
+    value
+        .and_then(Value::as_u64)
+        .and_then(|number| T::try_from(number).ok())
+        .ok_or(format!(
+            "Expected a number: {}",
+            value.unwrap_or(&Value::Null)
+        ))
+}
+
+fn array_from_json<'a>(value: &'a Value, key: &str) -> Result<&'a Vec<Value>, String> {
+    value
+        .get(key)
+        .and_then(Value::as_array)
+        .ok_or(format!("Missing {} in object file", key))
+}
+
+fn align_up(addr: u32, alignment: u16) -> u32 {
+    addr.div_ceil(alignment as u32) * alignment as u32
+}
+
+impl Object {
+    pub fn to_json(&self) -> String {
+        self.to_value().to_string()
+    }
+
+    pub fn from_json(str: &str) -> Result<Self, String> {
+        let value = serde_json::from_str::<Value>(str).map_err(|e| e.to_string())?;
+        Object::from_value(&value)
+    }
+
+    /// Symbols the object needs from other objects or from the linker.
+    pub fn external_symbols(&self) -> impl Iterator<Item = &str> {
+        self.relocations
+            .iter()
+            .filter_map(|relocation| match &relocation.target {
+                RelocationTarget::Symbol(symbol) => Some(symbol.as_str()),
+                RelocationTarget::Section(_) => None,
+            })
+    }
+
+    pub fn to_value(&self) -> Value {
+        let sections = self
+            .sections
+            .iter()
+            .map(|s| json!({ "name": s.name, "size": s.size, "alignment": s.alignment }))
+            .collect::<Vec<_>>();
+        let chunks = self
+            .chunks
+            .iter()
+            .map(|(location, bytes)| json!({ "location": location_to_json(location), "bytes": bytes }))
+            .collect::<Vec<_>>();
+        let symbols = self
+            .symbols
+            .iter()
+            .map(|(name, location)| json!({ "name": name, "location": location_to_json(location) }))
+            .collect::<Vec<_>>();
+        let relocations = self
+            .relocations
+            .iter()
+            .map(|relocation| match &relocation.target {
+                RelocationTarget::Section(section) => {
+                    json!({ "location": location_to_json(&relocation.location), "section": section })
+                }
+                RelocationTarget::Symbol(symbol) => {
+                    json!({ "location": location_to_json(&relocation.location), "symbol": symbol })
+                }
+            })
+            .collect::<Vec<_>>();
+        let page_assertions = self
+            .page_assertions
+            .iter()
+            .map(|p| {
+                json!({
+                    "label": p.label_name,
+                    "section": p.section,
+                    "start": p.start_addr,
+                    "end": p.end_addr,
+                    "line": p.line,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        json!({
+            "format": OBJECT_FORMAT,
+            "name": self.name,
+            "sections": sections,
+            "absolute_end": self.absolute_end,
+            "chunks": chunks,
+            "symbols": symbols,
+            "relocations": relocations,
+            "page_assertions": page_assertions,
+        })
+    }
+
+    pub fn from_value(value: &Value) -> Result<Self, String> {
+        if value.get("format").and_then(Value::as_str) != Some(OBJECT_FORMAT) {
+            return Err("Not a tasm object file".to_string());
+        }
+
+        let mut sections = vec![];
+        for section in array_from_json(value, "sections")? {
+            sections.push(SectionLayout {
+                name: string_from_json(section.get("name").unwrap_or(&Value::Null))?,
+                size: number_from_json(section.get("size"))?,
+                alignment: number_from_json(section.get("alignment"))?,
+            });
+        }
+        let mut chunks = vec![];
+        for chunk in array_from_json(value, "chunks")? {
+            let bytes = array_from_json(chunk, "bytes")?
+                .iter()
+                .map(|byte| number_from_json::<u8>(Some(byte)))
+                .collect::<Result<Vec<_>, _>>()?;
+            chunks.push((
+                location_from_json(chunk.get("location").unwrap_or(&Value::Null))?,
+                bytes,
+            ));
+        }
+        let mut symbols = vec![];
+        for symbol in array_from_json(value, "symbols")? {
+            symbols.push((
+                string_from_json(symbol.get("name").unwrap_or(&Value::Null))?,
+                location_from_json(symbol.get("location").unwrap_or(&Value::Null))?,
+            ));
+        }
+        let mut relocations = vec![];
+        for relocation in array_from_json(value, "relocations")? {
+            let target = match (relocation.get("section"), relocation.get("symbol")) {
+                (Some(section), _) => RelocationTarget::Section(string_from_json(section)?),
+                (_, Some(symbol)) => RelocationTarget::Symbol(string_from_json(symbol)?),
+                _ => return Err(format!("Relocation without target: {}", relocation)),
+            };
+            relocations.push(Relocation {
+                location: location_from_json(relocation.get("location").unwrap_or(&Value::Null))?,
+                target,
+            });
+        }
+        let mut page_assertions = vec![];
+        for page_assertion in array_from_json(value, "page_assertions")? {
+            page_assertions.push(PageAssertion {
+                label_name: string_from_json(page_assertion.get("label").unwrap_or(&Value::Null))?,
+                section: match page_assertion.get("section") {
+                    None | Some(Value::Null) => None,
+                    Some(section) => Some(string_from_json(section)?),
+                },
+                start_addr: number_from_json(page_assertion.get("start"))?,
+                end_addr: number_from_json(page_assertion.get("end"))?,
+                line: number_from_json(page_assertion.get("line"))?,
+            });
+        }
+
+        Ok(Self {
+            name: string_from_json(value.get("name").unwrap_or(&Value::Null))?,
+            sections,
+            absolute_end: number_from_json(value.get("absolute_end"))?,
+            chunks,
+            symbols,
+            relocations,
+            page_assertions,
+        })
+    }
+
+    /// Combine objects into an image. The parts of a section coming from each object
+    /// follow each other, in the order of `objects`. Fails on duplicate or missing symbols.
+    pub fn link(
+        objects: &[Object],
+        linker_config: &LinkerConfig,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Option<Image> {
+        let mut layouts: Vec<SectionLayout> = vec![];
+        let mut offsets: HashMap<(usize, &str), u16> = HashMap::new();
+        for (index, object) in objects.iter().enumerate() {
+            for section in object.sections.iter() {
+                let position = match layouts.iter().position(|l| l.name == section.name) {
+                    Some(position) => position,
+                    None => {
+                        layouts.push(SectionLayout {
+                            name: section.name.to_string(),
+                            size: 0,
+                            alignment: 1,
+                        });
+                        layouts.len() - 1
+                    }
+                };
+                let layout = &mut layouts[position];
+                let offset = align_up(layout.size as u32, section.alignment);
+                if offset + section.size as u32 > 0x10000 {
+                    diagnostics.push(Diagnostic::error(
+                        format!("Section {} does not fit in the address space", section.name),
+                        None,
+                    ));
+                    return None;
+                }
+                offsets.insert((index, &section.name), offset as u16);
+                layout.size = offset as u16 + section.size;
+                layout.alignment = num::integer::lcm(layout.alignment, section.alignment);
+            }
+        }
+        let absolute_end = objects.iter().map(|o| o.absolute_end).max().unwrap_or(0);
+
+        let placements = match linker_config.place_sections(&layouts, absolute_end) {
+            Ok(placements) => placements,
+            Err(errors) => {
+                for err_str in errors {
+                    diagnostics.push(Diagnostic::error(err_str, None));
+                }
+                return None;
+            }
+        };
+        for layout in layouts.iter() {
+            let placement = placements[&layout.name];
+            diagnostics.push(Diagnostic::info(format!(
+                "section {} at {:#06x} loaded at {:#06x}",
+                layout.name, placement.run, placement.load
+            )));
+        }
+
+        let section_start = |index: usize, section: &str, load: bool| {
+            let placement = placements[section];
+            let base = if load { placement.load } else { placement.run };
+            base.wrapping_add(offsets.get(&(index, section)).copied().unwrap_or(0))
+        };
+        let address = |index: usize, location: &Location, load: bool| match &location.section {
+            Some(section) => section_start(index, section, load).wrapping_add(location.addr),
+            None => location.addr,
+        };
+
+        // Duplicate and missing symbols make the image unusable
+        let mut symbol_errors = 0;
+        let mut symbols: HashMap<String, (u16, &str)> = HashMap::new();
+        for (index, object) in objects.iter().enumerate() {
+            for (name, location) in object.symbols.iter() {
+                if let Some((_, other)) = symbols.get(name) {
+                    diagnostics.push(Diagnostic::error(
+                        format!(
+                            "Symbol {} is defined in {} and {}",
+                            name, other, object.name
+                        ),
+                        None,
+                    ));
+                    symbol_errors += 1;
+                    continue;
+                }
+                symbols.insert(
+                    name.to_string(),
+                    (address(index, location, false), &object.name),
+                );
+            }
+        }
+        for layout in layouts.iter() {
+            let placement = placements[&layout.name];
+            for (suffix, value) in [
+                ("start", placement.run),
+                ("load", placement.load),
+                ("size", layout.size),
+            ] {
+                symbols
+                    .entry(format!("__{}_{}", layout.name, suffix))
+                    .or_insert((value, "linker"));
+            }
+        }
+
+        let mut chunks = vec![];
+        for (index, object) in objects.iter().enumerate() {
+            // Chunks at the same location are kept to be reported as overlapping,
+            // relocations apply to the first one which is the instruction
+            let mut object_chunks = object
+                .chunks
+                .iter()
+                .map(|(location, bytes)| (location, bytes.clone()))
+                .collect::<Vec<_>>();
+            let mut chunk_indexes: HashMap<&Location, usize> = HashMap::new();
+            for (chunk_index, (location, _)) in object_chunks.iter().enumerate() {
+                chunk_indexes.entry(*location).or_insert(chunk_index);
+            }
+            for relocation in object.relocations.iter() {
+                let Some(chunk_index) = chunk_indexes.get(&relocation.location) else {
+                    continue;
+                };
+                let bytes = &mut object_chunks[*chunk_index].1;
+                if bytes.len() < 3 {
+                    continue;
+                }
+                let stored_addr = u16::from_le_bytes([bytes[1], bytes[2]]);
+                let addr = match &relocation.target {
+                    RelocationTarget::Section(section) => {
+                        section_start(index, section, false).wrapping_add(stored_addr)
+                    }
+                    RelocationTarget::Symbol(symbol) => match symbols.get(symbol) {
+                        Some((addr, _)) => *addr,
+                        None => {
+                            diagnostics.push(Diagnostic::error(
+                                format!("Label {} didn't exist! | in {}", symbol, object.name),
+                                None,
+                            ));
+                            symbol_errors += 1;
+                            continue;
+                        }
+                    },
+                };
+                bytes[1..3].copy_from_slice(&addr.to_le_bytes());
+            }
+            chunks.extend(
+                object_chunks
+                    .into_iter()
+                    .map(|(location, bytes)| (address(index, location, true), bytes)),
+            );
+
+            for page_assertion in object.page_assertions.iter() {
+                // A block can end at $10000, the top of the memory
+                let run_start = |section: &Option<String>| match section {
+                    Some(section) => section_start(index, section, false) as u32,
+                    None => 0,
+                };
+                let start_addr =
+                    run_start(&page_assertion.section) + page_assertion.start_addr as u32;
+                let end_addr = run_start(&page_assertion.section) + page_assertion.end_addr;
+                if let Some(err_str) = page_assertion.crossing_error(start_addr, end_addr) {
+                    diagnostics.push(Diagnostic::error(err_str, Some(page_assertion.line)));
+                }
+            }
+        }
+        if symbol_errors > 0 {
+            return None;
+        }
+        chunks.sort_by_key(|(addr, _)| *addr);
+
+        // A chunk written over another one would silently replace its bytes
+        let mut overlap_errors = 0;
+        let mut previous: Option<(u16, usize)> = None;
+        for (addr, bytes) in chunks.iter().filter(|(_, bytes)| !bytes.is_empty()) {
+            let end_addr = *addr as usize + bytes.len();
+            match previous {
+                Some((previous_addr, previous_end)) if (*addr as usize) < previous_end => {
+                    diagnostics.push(Diagnostic::error(
+                        format!(
+                            "Bytes at {:#06x} overlap the bytes at {:#06x}",
+                            addr, previous_addr
+                        ),
+                        None,
+                    ));
+                    overlap_errors += 1;
+                    previous = Some((previous_addr, previous_end.max(end_addr)));
+                }
+                _ => previous = Some((*addr, end_addr)),
+            }
+        }
+        if overlap_errors > 0 {
+            return None;
+        }
+
+        Some(Image { chunks })
+    }
+}
+
+impl Image {
+    /// Size from the lowest address to the end of the last chunk, up to $10000 bytes.
+    pub fn bytes_size(&self) -> usize {
+        if self.chunks.is_empty() {
+            0
+        } else {
+            let min_address = self.chunks[0].0 as usize;
+            let max_end_address = self
+                .chunks
+                .iter()
+                .map(|(addr, bytes)| *addr as usize + bytes.len())
+                .max()
+                .unwrap();
+
+            max_end_address - min_address
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_bytes_filled(0)
+    }
+
+    /// Bytes from the lowest address, the gaps between chunks are set to `fill`.
+    pub fn to_bytes_filled(&self, fill: u8) -> Vec<u8> {
+        let size = self.bytes_size();
+        let mut memory = vec![fill; size];
+
+        if let Some((offset_addr, _)) = self.chunks.first() {
+            let offset_addr = *offset_addr;
+            for (addr, bytes_rep) in self.chunks.iter() {
+                let bytes_addr_slice = (addr - offset_addr) as usize
+                    ..((addr - offset_addr) as usize + bytes_rep.len());
+
+                memory[bytes_addr_slice].copy_from_slice(bytes_rep.as_slice());
+            }
+        }
+
+        memory
+    }
+
+    /// Write the image in `format`, the gaps between chunks are set to `fill`.
+    pub fn encode(&self, format: OutputFormat, fill: u8) -> Vec<u8> {
+        let bytes = self.to_bytes_filled(fill);
+        match format {
+            OutputFormat::Bin => bytes,
+            OutputFormat::Coe => to_coe(&bytes).into_bytes(),
+        }
+    }
+}
+
+/// COE memory file with one byte per line.
+fn to_coe(bytes: &[u8]) -> String {
+    let mut coe = String::from("memory_initialization_radix=16;\nmemory_initialization_vector=\n");
+    for (idx, byte) in bytes.iter().enumerate() {
+        let separator = if idx == bytes.len() - 1 { ';' } else { ',' };
+        coe.push_str(&format!("{:02x}{}\n", byte, separator));
+    }
+    coe
+}
+
+/// File formats an image can be written in.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OutputFormat {
+    /// Raw bytes from the lowest address
+    Bin,
+    /// Xilinx memory initialization file
+    Coe,
+}
+
+impl OutputFormat {
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "bin" => Some(OutputFormat::Bin),
+            "coe" => Some(OutputFormat::Coe),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Bin => "bin",
+            OutputFormat::Coe => "coe",
+        }
+    }
+}
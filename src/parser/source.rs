@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Where the root file and the files it includes are read from.
+pub trait SourceProvider {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, String>;
+
+    fn exists(&self, path: &Path) -> bool;
+
+    fn read_to_string(&self, path: &Path) -> Result<String, String> {
+        String::from_utf8(self.read(path)?)
+            .map_err(|_| format!("File is not valid UTF-8: {}", path.display()))
+    }
+
+    /// Path identifying a file, to detect include cycles and `.include_once`.
+    fn canonicalize(&self, path: &Path) -> PathBuf {
+        normalize(path)
+    }
+}
+
+/// A borrowed provider, e.g. to layer an unsaved buffer over the provider of a caller.
+impl<T: SourceProvider + ?Sized> SourceProvider for &T {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, String> {
+        (**self).read(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        (**self).exists(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> PathBuf {
+        (**self).canonicalize(path)
+    }
+}
+
+/// Remove `.` and `..` components without touching the filesystem.
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if normalized.file_name().is_some() => {
+                normalized.pop();
+            }
+            _ => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+fn not_found(path: &Path) -> String {
+    format!("Path does not exist: {}", path.display())
+}
+
+pub struct FileSystem;
+
+impl SourceProvider for FileSystem {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, String> {
+        fs::read(path).map_err(|_| not_found(path))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn canonicalize(&self, path: &Path) -> PathBuf {
+        fs::canonicalize(path).unwrap_or(path.to_path_buf())
+    }
+}
+
+/// Files held in memory, e.g. unsaved editor buffers or a bundled standard library.
+#[derive(Default)]
+pub struct MemorySources {
+    files: HashMap<PathBuf, Vec<u8>>,
+}
+
+impl MemorySources {
+    pub fn insert(&mut self, path: impl AsRef<Path>, contents: impl Into<Vec<u8>>) {
+        self.files.insert(normalize(path.as_ref()), contents.into());
+    }
+}
+
+impl SourceProvider for MemorySources {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, String> {
+        self.files
+            .get(&normalize(path))
+            .cloned()
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.files.contains_key(&normalize(path))
+    }
+}
+
+/// Providers searched in order, a file is read from the first one having it.
+#[derive(Default)]
+pub struct LayeredSources<'a> {
+    layers: Vec<Box<dyn SourceProvider + 'a>>,
+}
+
+impl<'a> LayeredSources<'a> {
+    pub fn push(mut self, layer: impl SourceProvider + 'a) -> Self {
+        self.layers.push(Box::new(layer));
+        self
+    }
+
+    fn layer(&self, path: &Path) -> Option<&(dyn SourceProvider + 'a)> {
+        self.layers
+            .iter()
+            .find(|layer| layer.exists(path))
+            .map(|layer| layer.as_ref())
+    }
+}
+
+impl SourceProvider for LayeredSources<'_> {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, String> {
+        self.layer(path).ok_or_else(|| not_found(path))?.read(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.layer(path).is_some()
+    }
+
+    fn canonicalize(&self, path: &Path) -> PathBuf {
+        match self.layer(path) {
+            Some(layer) => layer.canonicalize(path),
+            None => normalize(path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layered_sources() {
+        let mut memory = MemorySources::default();
+        memory.insert("lib/math.tasm", "rts");
+        memory.insert("test/test.tasm", "halt");
+        let sources = LayeredSources::default().push(memory).push(FileSystem);
+
+        assert_eq!(
+            normalize(Path::new("./lib/../lib/math.tasm")),
+            Path::new("lib/math.tasm")
+        );
+        assert_eq!(
+            sources.read_to_string(Path::new("./lib/x/../math.tasm")),
+            Ok("rts".to_string())
+        );
+        // Memory files hide the ones on disk
+        assert_eq!(
+            sources.read_to_string(Path::new("test/test.tasm")),
+            Ok("halt".to_string())
+        );
+        assert!(sources.exists(Path::new("test/set.tasm")));
+        assert!(!sources.exists(Path::new("test/missing.tasm")));
+        assert!(sources.read(Path::new("test/missing.tasm")).is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use crate::parser::source::SourceProvider;
+use std::cell::RefCell;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Modification time and size of a file, `None` while it does not exist.
+type Stamp = Option<(SystemTime, u64)>;
+
+fn stamp(path: &Path) -> Stamp {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Polls files for changes, including their creation or removal.
+pub struct Watcher {
+    files: Vec<(PathBuf, Stamp)>,
+}
+
+impl Watcher {
+    pub fn new(paths: &[PathBuf]) -> Self {
+        Self {
+            files: paths
+                .iter()
+                .map(|path| (path.clone(), stamp(path)))
+                .collect(),
+        }
+    }
+
+    /// Number of files watched.
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Files that changed since the watcher was created.
+    pub fn changed(&self) -> Vec<&Path> {
+        self.files
+            .iter()
+            .filter(|(path, previous)| stamp(path) != *previous)
+            .map(|(path, _)| path.as_path())
+            .collect()
+    }
+
+    /// Block until one of the files changes, checking every `interval`.
+    pub fn wait(&self, interval: Duration) -> Vec<&Path> {
+        loop {
+            let changed = self.changed();
+            if !changed.is_empty() {
+                return changed;
+            }
+            thread::sleep(interval);
+        }
+    }
+}
+
+/// Provider taking the stamp of every file before reading it, and of the paths probed
+/// without finding a file. Edits saved while assembling and files created where an
+/// include was searched are then seen by the watcher.
+pub struct StampingSources<'a> {
+    sources: &'a dyn SourceProvider,
+    files: RefCell<Vec<(PathBuf, Stamp)>>,
+}
+
+impl<'a> StampingSources<'a> {
+    pub fn new(sources: &'a dyn SourceProvider) -> Self {
+        Self {
+            sources,
+            files: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn record(&self, path: &Path, stamp: Stamp) {
+        let mut files = self.files.borrow_mut();
+        if !files.iter().any(|(file, _)| file == path) {
+            files.push((path.to_path_buf(), stamp));
+        }
+    }
+
+    /// Watcher of the files read or probed so far, compared to their stamps at that time.
+    pub fn into_watcher(self) -> Watcher {
+        Watcher {
+            files: self.files.into_inner(),
+        }
+    }
+}
+
+impl SourceProvider for StampingSources<'_> {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, String> {
+        self.record(path, stamp(path));
+        self.sources.read(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let previous = stamp(path);
+        let exists = self.sources.exists(path);
+        if !exists {
+            self.record(path, previous);
+        }
+        exists
+    }
+
+    fn canonicalize(&self, path: &Path) -> PathBuf {
+        self.sources.canonicalize(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watcher() {
+        let dir = std::env::temp_dir().join(format!("tasm_watch_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("main.tasm");
+        let lib = dir.join("lib.tasm");
+        fs::write(&main, "halt").unwrap();
+
+        let watcher = Watcher::new(&[main.clone(), lib.clone()]);
+        assert!(watcher.changed().is_empty());
+
+        // A file created after the watcher counts as a change
+        fs::write(&lib, "rts").unwrap();
+        assert_eq!(watcher.changed(), vec![lib.as_path()]);
+
+        let watcher = Watcher::new(&[main.clone(), lib.clone()]);
+        let writer = {
+            let main = main.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                fs::write(main, "load rx,#5\nhalt").unwrap();
+            })
+        };
+        assert_eq!(
+            watcher.wait(Duration::from_millis(10)),
+            vec![main.as_path()]
+        );
+        writer.join().unwrap();
+
+        // Stamps are taken when files are read, an edit saved afterwards is a change
+        let sources = StampingSources::new(&crate::FileSystem);
+        sources.read(&main).unwrap();
+        fs::remove_file(&lib).unwrap();
+        assert!(!sources.exists(&lib));
+        let watcher = sources.into_watcher();
+        assert!(watcher.changed().is_empty());
+        thread::sleep(Duration::from_millis(10));
+        fs::write(&main, "halt\nhalt").unwrap();
+        fs::write(&lib, "rts").unwrap();
+        assert_eq!(watcher.changed(), vec![main.as_path(), lib.as_path()]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}